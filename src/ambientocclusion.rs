@@ -0,0 +1,75 @@
+use renderer::{RendererBase, AbstractRenderer};
+use rng::{Rng, MathRng};
+use scene::Scene;
+use frame::Frame;
+use utils::sample_cos_hemisphere_w;
+use std::rand::SeedableRng;
+use math::{vec2, vec2s, vec3s};
+use ray::{Ray, Isect};
+
+/// Renders ambient occlusion, casting one cosine-distributed shadow ray per pixel.
+pub struct AmbientOcclusion<'a> {
+    base: RendererBase<'a>,
+    rng: Rng,
+    max_distance: f32,
+}
+
+impl<'a> AmbientOcclusion<'a> {
+    pub fn new(scene: &Scene, seed: u32, max_distance: f32) -> AmbientOcclusion {
+        AmbientOcclusion {
+            base: RendererBase::new(scene),
+            rng: SeedableRng::from_seed([0, seed as u64]),
+            max_distance: max_distance,
+        }
+    }
+}
+
+impl<'a> AbstractRenderer<'a> for AmbientOcclusion<'a> {
+    fn base<'b>(&'b self) -> &'b RendererBase<'a> {
+        &self.base
+    }
+
+    fn base_mut<'b>(&'b mut self) -> &'b mut RendererBase<'a> {
+        &mut self.base
+    }
+
+    fn run_iteration(&mut self, iteration: u32) {
+        let scene = self.base.scene;
+        let window = self.base.trace_window;
+
+        for pix_id in range(0, window.num_pixels()) {
+            let (x, y) = window.pixel(pix_id);
+
+            let sample = vec2(x as f32, y as f32) +
+                if iteration == 0 { vec2s(0.5) } else { self.rng.get_vec2f() };
+
+            let time = if iteration == 0 { 0.5 } else { self.rng.get_f32() };
+            let ray = scene.camera.generate_ray(sample, self.rng.get_vec2f(), time);
+            let mut isect = Isect { dist: 1e36, ..Isect::new() };
+
+            if !scene.intersect(&ray, &mut isect) {
+                self.base.film.add_color(sample, vec3s(0.0));
+                continue;
+            }
+
+            // Surfaces are two-sided, occlusion is computed on the side facing the camera
+            let normal = if isect.geom_normal.dot(ray.dir) > 0.0 {
+                -isect.geom_normal
+            } else {
+                isect.geom_normal
+            };
+
+            let (local_dir, _) = sample_cos_hemisphere_w(self.rng.get_vec2f());
+            let dir = Frame::from_z(normal).to_world(local_dir);
+
+            // Shadow rays go straight to the geometry's occlusion query
+            let shadow_ray = Ray { tmax: self.max_distance, ..Ray::spawn(&isect, dir) };
+
+            // The cosine is cancelled by the pdf, so unoccluded samples contribute exactly one
+            let visibility = if scene.geometry().occluded(&shadow_ray) { 0.0 } else { 1.0 };
+            self.base.film.add_color(sample, vec3s(visibility));
+        }
+
+        self.base.iterations += 1;
+    }
+}
@@ -0,0 +1,140 @@
+use renderer::{RendererBase, AbstractRenderer};
+use rng::{Rng, MathRng};
+use scene::Scene;
+use bsdf::Bsdf;
+use std::rand::SeedableRng;
+use std::num::{Float, FloatMath};
+use math::{Vec3f, vec2, vec2s, vec3, vec3s};
+use ray::Isect;
+
+#[deriving(Copy)]
+pub enum Aov {
+    ShadingNormal,
+    Depth,
+    MaterialId,
+    LightId,
+    Albedo,
+    BsdfDirection,
+}
+
+impl Aov {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Aov::ShadingNormal => "normal",
+            Aov::Depth => "depth",
+            Aov::MaterialId => "matid",
+            Aov::LightId => "lightid",
+            Aov::Albedo => "albedo",
+            Aov::BsdfDirection => "bsdfdir",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Aov> {
+        Some(match s {
+            "normal"  => Aov::ShadingNormal,
+            "depth"   => Aov::Depth,
+            "matid"   => Aov::MaterialId,
+            "lightid" => Aov::LightId,
+            "albedo"  => Aov::Albedo,
+            "bsdfdir" => Aov::BsdfDirection,
+            _ => return None,
+        })
+    }
+}
+
+/// Maps an id to an arbitrary but well separated color. Negative ids (none) are black.
+fn false_color(id: int) -> Vec3f {
+    if id < 0 {
+        return vec3s(0.0);
+    }
+
+    // Stepping the hue by the golden ratio keeps consecutive ids distinguishable
+    let hue = (id as f32 * 0.618034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+
+    match hue as uint {
+        0 => vec3(1.0, x, 0.0),
+        1 => vec3(x, 1.0, 0.0),
+        2 => vec3(0.0, 1.0, x),
+        3 => vec3(0.0, x, 1.0),
+        4 => vec3(x, 0.0, 1.0),
+        _ => vec3(1.0, 0.0, x),
+    }
+}
+
+/// Maps a unit vector to a displayable color.
+fn direction_color(dir: Vec3f) -> Vec3f {
+    dir * vec3s(0.5) + vec3s(0.5)
+}
+
+/// Debug renderer, outputting a single arbitrary output value (AOV) of the first hit.
+pub struct AovRenderer<'a> {
+    base: RendererBase<'a>,
+    rng: Rng,
+    aov: Aov,
+}
+
+impl<'a> AovRenderer<'a> {
+    pub fn new(scene: &Scene, seed: u32, aov: Aov) -> AovRenderer {
+        AovRenderer {
+            base: RendererBase::new(scene),
+            rng: SeedableRng::from_seed([0, seed as u64]),
+            aov: aov,
+        }
+    }
+}
+
+impl<'a> AbstractRenderer<'a> for AovRenderer<'a> {
+    fn base<'b>(&'b self) -> &'b RendererBase<'a> {
+        &self.base
+    }
+
+    fn base_mut<'b>(&'b mut self) -> &'b mut RendererBase<'a> {
+        &mut self.base
+    }
+
+    fn run_iteration(&mut self, iteration: u32) {
+        let scene = self.base.scene;
+        let window = self.base.trace_window;
+
+        for pix_id in range(0, window.num_pixels()) {
+            let (x, y) = window.pixel(pix_id);
+
+            let sample = vec2(x as f32, y as f32) +
+                if iteration == 0 { vec2s(0.5) } else { self.rng.get_vec2f() };
+
+            let time = if iteration == 0 { 0.5 } else { self.rng.get_f32() };
+            let ray = scene.camera.generate_ray(sample, self.rng.get_vec2f(), time);
+            let mut isect = Isect { dist: 1e36, ..Isect::new() };
+
+            if !scene.intersect(&ray, &mut isect) {
+                self.base.film.add_color(sample, vec3s(0.0));
+                continue;
+            }
+
+            let color = match self.aov {
+                Aov::ShadingNormal => direction_color(isect.shading_normal),
+                // Normalized so the whole scene fits in [0, 1]
+                Aov::Depth => vec3s(isect.dist / (2.0 * scene.scene_sphere().scene_radius)),
+                Aov::MaterialId => false_color(isect.mat_id),
+                Aov::LightId => false_color(isect.light_id),
+                Aov::Albedo => scene.get_material(isect.mat_id).diffuse_reflectance,
+                Aov::BsdfDirection => {
+                    let bsdf = Bsdf::new(&ray, &isect, scene, false);
+                    if !bsdf.is_valid() {
+                        vec3s(0.0)
+                    } else {
+                        match bsdf.sample(scene, self.rng.get_vec3f()) {
+                            Some(bsdf_sample) => direction_color(bsdf_sample.world_dir_gen),
+                            None => vec3s(0.0),
+                        }
+                    }
+                },
+            };
+
+            self.base.film.add_color(sample, color);
+        }
+
+        self.base.iterations += 1;
+    }
+}
@@ -0,0 +1,445 @@
+use math::{Vec2f, Vec3f, vec3, vec3s, sqr, INV_PI};
+use frame::Frame;
+use materials::Material;
+use ray::{Ray, Isect};
+use scene::Scene;
+use utils::{luminance, fresnel_dielectric, reflect_local, EPS_COSINE,
+            sample_cos_hemisphere_w, sample_power_cos_hemisphere_w, power_cos_hemisphere_pdf_w};
+use std::num::Float;
+
+const EPS_PHONG: f32 = 1e-3;
+
+bitflags! {
+    flags Events: u32 {
+        const NONE    = 0,
+        const DIFFUSE = 1,
+        const PHONG   = 2,
+        const REFLECT = 4,
+        const REFRACT = 8,
+
+        const SPECULAR     = REFLECT.bits | REFRACT.bits,
+        const NON_SPECULAR = DIFFUSE.bits | PHONG.bits,
+        const ALL          = SPECULAR.bits | NON_SPECULAR.bits,
+    }
+}
+
+struct ComponentProbabilities {
+    diff_prob: f32,
+    phong_prob: f32,
+    refl_prob: f32,
+    refr_prob: f32,
+}
+
+pub struct BsdfEval {
+    pub color: Vec3f,
+    pub cos_theta_gen: f32,
+    pub direct_pdf_w: f32,
+    pub reverse_pdf_w: f32,
+}
+
+pub struct BsdfSample {
+    pub color: Vec3f,
+    pub world_dir_gen: Vec3f,
+    pub pdf_w: f32,
+    pub cos_theta_gen: f32,
+    pub event: Events,
+}
+
+/// BSDF at a surface point, set up for a fixed incoming direction. "Fix" is the direction of the
+/// path being extended and "Gen" the direction being generated/evaluated.
+///
+/// `fix_light` should be set when tracing light paths: it changes which direction radiance flows,
+/// which matters for refraction and shading normals.
+///
+/// The frame is built around the shading normal, but the geometric normal is still used to reject
+/// directions that would cross the actual surface.
+pub struct Bsdf {
+    material_id: int,
+    frame: Frame,
+    local_dir_fix: Vec3f,
+    local_geom_normal: Vec3f,
+    is_delta: bool,
+    fix_light: bool,
+    probabilities: ComponentProbabilities,
+    continuation_prob: f32,
+    reflect_coeff: f32,
+    ior: f32,
+}
+
+fn is_zero(v: Vec3f) -> bool {
+    v.x == 0.0 && v.y == 0.0 && v.z == 0.0
+}
+
+impl Bsdf {
+    pub fn new(ray: &Ray, isect: &Isect, scene: &Scene, fix_light: bool) -> Bsdf {
+        let frame = isect.frame;
+        let local_dir_fix = frame.to_local(-ray.dir);
+
+        let mut bsdf = Bsdf {
+            material_id: -1,
+            frame: frame,
+            local_dir_fix: local_dir_fix,
+            local_geom_normal: frame.to_local(isect.geom_normal),
+            is_delta: false,
+            fix_light: fix_light,
+            probabilities: ComponentProbabilities {
+                diff_prob: 0.0, phong_prob: 0.0, refl_prob: 0.0, refr_prob: 0.0 },
+            continuation_prob: 0.0,
+            reflect_coeff: 0.0,
+            ior: -1.0,
+        };
+
+        // Reject rays that are too parallel with the tangent plane
+        if local_dir_fix.z.abs() < EPS_COSINE {
+            return bsdf;
+        }
+
+        let mat = scene.get_material(isect.mat_id);
+        bsdf.ior = mat.ior;
+        bsdf.compute_probabilities(mat);
+        bsdf.is_delta = bsdf.probabilities.diff_prob == 0.0 && bsdf.probabilities.phong_prob == 0.0;
+
+        // Now it becomes valid
+        bsdf.material_id = isect.mat_id;
+        bsdf
+    }
+
+    pub fn is_valid(&self) -> bool { self.material_id >= 0 }
+    pub fn is_delta(&self) -> bool { self.is_delta }
+    pub fn continuation_prob(&self) -> f32 { self.continuation_prob }
+    pub fn cos_theta_fix(&self) -> f32 { self.local_dir_fix.z }
+    pub fn world_dir_fix(&self) -> Vec3f { self.frame.to_world(self.local_dir_fix) }
+
+    /// Evaluates the non-specular components for the given direction.
+    pub fn evaluate(&self, scene: &Scene, world_dir_gen: Vec3f) -> BsdfEval {
+        let mut eval = BsdfEval {
+            color: vec3s(0.0),
+            cos_theta_gen: 0.0,
+            direct_pdf_w: 0.0,
+            reverse_pdf_w: 0.0,
+        };
+
+        let local_dir_gen = self.frame.to_local(world_dir_gen);
+
+        if local_dir_gen.z * self.local_dir_fix.z < 0.0 {
+            return eval;
+        }
+
+        if !self.same_geometric_side(local_dir_gen) {
+            return eval;
+        }
+
+        eval.cos_theta_gen = local_dir_gen.z.abs();
+
+        let mat = scene.get_material(self.material_id);
+        let diffuse = self.evaluate_diffuse(mat, local_dir_gen,
+                                            &mut eval.direct_pdf_w, &mut eval.reverse_pdf_w);
+        let phong = self.evaluate_phong(mat, local_dir_gen,
+                                        &mut eval.direct_pdf_w, &mut eval.reverse_pdf_w);
+        eval.color = (diffuse + phong) * vec3s(self.shading_normal_correction(local_dir_gen));
+
+        eval
+    }
+
+    /// Pdf of sampling `world_dir_gen` with `sample`, or of the reverse direction if
+    /// `eval_rev_pdf` is set. Specular components are not considered.
+    pub fn pdf(&self, scene: &Scene, world_dir_gen: Vec3f, eval_rev_pdf: bool) -> f32 {
+        let local_dir_gen = self.frame.to_local(world_dir_gen);
+
+        if local_dir_gen.z * self.local_dir_fix.z < 0.0 {
+            return 0.0;
+        }
+
+        let mat = scene.get_material(self.material_id);
+
+        let mut direct_pdf_w = 0.0;
+        let mut reverse_pdf_w = 0.0;
+        self.pdf_diffuse(local_dir_gen, &mut direct_pdf_w, &mut reverse_pdf_w);
+        self.pdf_phong(mat, local_dir_gen, &mut direct_pdf_w, &mut reverse_pdf_w);
+
+        if eval_rev_pdf { reverse_pdf_w } else { direct_pdf_w }
+    }
+
+    /// Samples a direction, picking a component using `rnd_triplet.z`.
+    pub fn sample(&self, scene: &Scene, rnd_triplet: Vec3f) -> Option<BsdfSample> {
+        let probs = &self.probabilities;
+        let event =
+            if rnd_triplet.z < probs.diff_prob {
+                DIFFUSE
+            } else if rnd_triplet.z < probs.diff_prob + probs.phong_prob {
+                PHONG
+            } else if rnd_triplet.z < probs.diff_prob + probs.phong_prob + probs.refl_prob {
+                REFLECT
+            } else {
+                REFRACT
+            };
+
+        let mat = scene.get_material(self.material_id);
+        let rnd_tuple = rnd_triplet.get_xy();
+
+        let mut pdf_w = 0.0;
+        let mut unused_pdf_w = 0.0;
+
+        let sampled = if event == DIFFUSE {
+            match self.sample_diffuse(mat, rnd_tuple, &mut pdf_w) {
+                Some((dir, color)) => Some((dir, color +
+                    self.evaluate_phong(mat, dir, &mut pdf_w, &mut unused_pdf_w))),
+                None => None,
+            }
+        } else if event == PHONG {
+            match self.sample_phong(mat, rnd_tuple, &mut pdf_w) {
+                Some((dir, color)) => Some((dir, color +
+                    self.evaluate_diffuse(mat, dir, &mut pdf_w, &mut unused_pdf_w))),
+                None => None,
+            }
+        } else if event == REFLECT {
+            self.sample_reflect(mat, &mut pdf_w)
+        } else {
+            self.sample_refract(&mut pdf_w)
+        };
+
+        let (local_dir_gen, color) = match sampled {
+            Some(x) => x,
+            None => return None,
+        };
+
+        let cos_theta_gen = local_dir_gen.z.abs();
+        if cos_theta_gen < EPS_COSINE {
+            return None;
+        }
+
+        // Reflected directions must stay on the same side of the surface, refracted ones must not
+        if self.same_geometric_side(local_dir_gen) == (event == REFRACT) {
+            return None;
+        }
+
+        Some(BsdfSample {
+            color: color * vec3s(self.shading_normal_correction(local_dir_gen)),
+            world_dir_gen: self.frame.to_world(local_dir_gen),
+            pdf_w: pdf_w,
+            cos_theta_gen: cos_theta_gen,
+            event: event,
+        })
+    }
+
+    /// Whether the generated direction is on the same side of the geometric surface as the fixed
+    /// one. When using shading normals this can disagree with the test in the shading frame.
+    fn same_geometric_side(&self, local_dir_gen: Vec3f) -> bool {
+        self.local_dir_fix.dot(self.local_geom_normal) *
+            local_dir_gen.dot(self.local_geom_normal) > 0.0
+    }
+
+    /// Shading normals make the BSDF non-symmetric, so light paths need to be corrected by the
+    /// ratio of the cosines against both normals. See Veach's thesis, section 5.3.
+    fn shading_normal_correction(&self, local_dir_gen: Vec3f) -> f32 {
+        if !self.fix_light {
+            return 1.0;
+        }
+
+        let num = self.local_dir_fix.z.abs() * local_dir_gen.dot(self.local_geom_normal).abs();
+        let denom = self.local_dir_fix.dot(self.local_geom_normal).abs() * local_dir_gen.z.abs();
+
+        if denom == 0.0 { 0.0 } else { num / denom }
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Sampling methods
+
+    fn sample_diffuse(&self, mat: &Material, rnd_tuple: Vec2f,
+                      pdf_w: &mut f32) -> Option<(Vec3f, Vec3f)> {
+        if self.local_dir_fix.z < EPS_COSINE {
+            return None;
+        }
+
+        let (local_dir_gen, unweighted_pdf_w) = sample_cos_hemisphere_w(rnd_tuple);
+        *pdf_w += unweighted_pdf_w * self.probabilities.diff_prob;
+
+        Some((local_dir_gen, mat.diffuse_reflectance * vec3s(INV_PI)))
+    }
+
+    fn sample_phong(&self, mat: &Material, rnd_tuple: Vec2f,
+                    pdf_w: &mut f32) -> Option<(Vec3f, Vec3f)> {
+        let (lobe_dir, _) = sample_power_cos_hemisphere_w(rnd_tuple, mat.phong_exponent);
+
+        // Due to numeric issues in MIS, we actually need to compute all pdfs
+        // exactly the same way all the time!!!
+        let refl_local_dir_fixed = reflect_local(self.local_dir_fix);
+        let local_dir_gen = Frame::from_z(refl_local_dir_fixed).to_world(lobe_dir);
+
+        let dot_r_wi = refl_local_dir_fixed.dot(local_dir_gen);
+
+        if dot_r_wi <= EPS_PHONG {
+            return None;
+        }
+
+        let mut unused_pdf_w = 0.0;
+        self.pdf_phong(mat, local_dir_gen, pdf_w, &mut unused_pdf_w);
+
+        let rho = mat.phong_reflectance * vec3s((mat.phong_exponent + 2.0) * 0.5 * INV_PI);
+        Some((local_dir_gen, rho * vec3s(dot_r_wi.powf(mat.phong_exponent))))
+    }
+
+    fn sample_reflect(&self, mat: &Material, pdf_w: &mut f32) -> Option<(Vec3f, Vec3f)> {
+        let local_dir_gen = reflect_local(self.local_dir_fix);
+
+        *pdf_w += self.probabilities.refl_prob;
+        // BSDF is multiplied (outside) by cosine (local_dir_gen.z),
+        // for mirror this shouldn't be done, so we pre-divide here instead
+        let color = mat.mirror_reflectance *
+            vec3s(self.reflect_coeff / local_dir_gen.z.abs());
+
+        if is_zero(color) { None } else { Some((local_dir_gen, color)) }
+    }
+
+    fn sample_refract(&self, pdf_w: &mut f32) -> Option<(Vec3f, Vec3f)> {
+        if self.ior < 0.0 {
+            return None;
+        }
+
+        let cos_i = self.local_dir_fix.z;
+
+        let (cos_i, cos_t_sign, eta_inc_over_eta_trans) = if cos_i < 0.0 {
+            // Hit from inside
+            (-cos_i, 1.0, self.ior)
+        } else {
+            (cos_i, -1.0, 1.0 / self.ior)
+        };
+
+        let sin_i2 = 1.0 - cos_i * cos_i;
+        let sin_t2 = sqr(eta_inc_over_eta_trans) * sin_i2;
+
+        if sin_t2 >= 1.0 {
+            // Total internal reflection
+            return None;
+        }
+
+        let cos_t = cos_t_sign * (1.0 - sin_t2).max(0.0).sqrt();
+
+        let local_dir_gen = vec3(
+            -eta_inc_over_eta_trans * self.local_dir_fix.x,
+            -eta_inc_over_eta_trans * self.local_dir_fix.y,
+            cos_t);
+
+        *pdf_w += self.probabilities.refr_prob;
+
+        let refract_coeff = 1.0 - self.reflect_coeff;
+        // Only camera paths are multiplied by this factor, and etas
+        // are swapped because radiance flows in the opposite direction
+        let color = if !self.fix_light {
+            vec3s(refract_coeff * sqr(eta_inc_over_eta_trans) / cos_t.abs())
+        } else {
+            vec3s(refract_coeff / cos_t.abs())
+        };
+
+        Some((local_dir_gen, color))
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Evaluation methods
+
+    fn evaluate_diffuse(&self, mat: &Material, local_dir_gen: Vec3f,
+                        direct_pdf_w: &mut f32, reverse_pdf_w: &mut f32) -> Vec3f {
+        if self.probabilities.diff_prob == 0.0 {
+            return vec3s(0.0);
+        }
+
+        if self.local_dir_fix.z < EPS_COSINE || local_dir_gen.z < EPS_COSINE {
+            return vec3s(0.0);
+        }
+
+        self.pdf_diffuse(local_dir_gen, direct_pdf_w, reverse_pdf_w);
+
+        mat.diffuse_reflectance * vec3s(INV_PI)
+    }
+
+    fn evaluate_phong(&self, mat: &Material, local_dir_gen: Vec3f,
+                      direct_pdf_w: &mut f32, reverse_pdf_w: &mut f32) -> Vec3f {
+        if self.probabilities.phong_prob == 0.0 {
+            return vec3s(0.0);
+        }
+
+        if self.local_dir_fix.z < EPS_COSINE || local_dir_gen.z < EPS_COSINE {
+            return vec3s(0.0);
+        }
+
+        let refl_local_dir_in = reflect_local(self.local_dir_fix);
+        let dot_r_wi = refl_local_dir_in.dot(local_dir_gen);
+
+        if dot_r_wi <= EPS_PHONG {
+            return vec3s(0.0);
+        }
+
+        self.pdf_phong(mat, local_dir_gen, direct_pdf_w, reverse_pdf_w);
+
+        let rho = mat.phong_reflectance * vec3s((mat.phong_exponent + 2.0) * 0.5 * INV_PI);
+        rho * vec3s(dot_r_wi.powf(mat.phong_exponent))
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Pdf methods
+
+    fn pdf_diffuse(&self, local_dir_gen: Vec3f, direct_pdf_w: &mut f32, reverse_pdf_w: &mut f32) {
+        if self.probabilities.diff_prob == 0.0 {
+            return;
+        }
+
+        *direct_pdf_w += self.probabilities.diff_prob * (local_dir_gen.z * INV_PI).max(0.0);
+        *reverse_pdf_w += self.probabilities.diff_prob * (self.local_dir_fix.z * INV_PI).max(0.0);
+    }
+
+    fn pdf_phong(&self, mat: &Material, local_dir_gen: Vec3f,
+                 direct_pdf_w: &mut f32, reverse_pdf_w: &mut f32) {
+        if self.probabilities.phong_prob == 0.0 {
+            return;
+        }
+
+        let refl_local_dir_in = reflect_local(self.local_dir_fix);
+        let dot_r_wi = refl_local_dir_in.dot(local_dir_gen);
+
+        if dot_r_wi <= EPS_PHONG {
+            return;
+        }
+
+        // The sampling is symmetric
+        let pdf_w = self.probabilities.phong_prob *
+            power_cos_hemisphere_pdf_w(refl_local_dir_in, local_dir_gen, mat.phong_exponent);
+
+        *direct_pdf_w += pdf_w;
+        *reverse_pdf_w += pdf_w;
+    }
+
+    //////////////////////////////////////////////////////////////////////////
+    // Albedo methods
+
+    fn compute_probabilities(&mut self, mat: &Material) {
+        self.reflect_coeff = fresnel_dielectric(self.local_dir_fix.z, mat.ior);
+
+        let albedo_diffuse = luminance(mat.diffuse_reflectance);
+        let albedo_phong   = luminance(mat.phong_reflectance);
+        let albedo_reflect = self.reflect_coeff * luminance(mat.mirror_reflectance);
+        let albedo_refract = (1.0 - self.reflect_coeff) * if mat.ior > 0.0 { 1.0 } else { 0.0 };
+
+        let total_albedo = albedo_diffuse + albedo_phong + albedo_reflect + albedo_refract;
+
+        if total_albedo < 1e-9 {
+            self.probabilities = ComponentProbabilities {
+                diff_prob: 0.0, phong_prob: 0.0, refl_prob: 0.0, refr_prob: 0.0 };
+            self.continuation_prob = 0.0;
+        } else {
+            self.probabilities = ComponentProbabilities {
+                diff_prob:  albedo_diffuse / total_albedo,
+                phong_prob: albedo_phong   / total_albedo,
+                refl_prob:  albedo_reflect / total_albedo,
+                refr_prob:  albedo_refract / total_albedo,
+            };
+
+            // The continuation probability is max component from reflectance.
+            // That way the weight of sample will never rise.
+            // Luminance is another very valid option.
+            let continuation = (mat.diffuse_reflectance + mat.phong_reflectance +
+                                mat.mirror_reflectance * vec3s(self.reflect_coeff)).max() +
+                               (1.0 - self.reflect_coeff);
+            self.continuation_prob = continuation.max(0.0).min(1.0);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use math::{Vec3f, Aabb, vec3s};
+use ray::{Ray, Isect};
+use std::cmp::Ordering;
+
+/// A set of primitives that can be stored in a `Bvh`. Primitives are referenced by index.
+pub trait BvhPrimitives {
+    fn intersect_primitive(&self, index: uint, ray: &Ray, result: &mut Isect) -> bool;
+    fn occluded_primitive(&self, index: uint, ray: &Ray) -> bool;
+}
+
+const MAX_LEAF_PRIMITIVES: uint = 4;
+const NUM_SAH_BINS: uint = 12;
+/// Size of the traversal stack. The build keeps leaves shallower than this, which bounds the
+/// number of pending nodes.
+const MAX_TRAVERSAL_DEPTH: uint = 64;
+
+struct BvhNode {
+    bbox: Aabb,
+    /// For leaves, the first entry in `prim_indices`. For interior nodes, the index of the second
+    /// child. The first child always immediately follows its parent.
+    offset: uint,
+    /// Number of primitives in a leaf, 0 for interior nodes.
+    count: uint,
+}
+
+struct PrimInfo {
+    index: uint,
+    bbox: Aabb,
+    centroid: Vec3f,
+}
+
+/// Bounding volume hierarchy, built using binned SAH.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    prim_indices: Vec<uint>,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over primitives with the given bounding boxes.
+    pub fn build(bboxes: &[Aabb]) -> Bvh {
+        let mut infos: Vec<PrimInfo> = bboxes.iter().enumerate().map(|(i, bbox)| {
+            PrimInfo {
+                index: i,
+                bbox: *bbox,
+                centroid: bbox.centroid(),
+            }
+        }).collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            prim_indices: Vec::with_capacity(infos.len()),
+        };
+
+        if !infos.is_empty() {
+            bvh.build_recursive(infos[mut], 0);
+        }
+
+        bvh
+    }
+
+    fn build_recursive(&mut self, infos: &mut [PrimInfo], depth: uint) -> uint {
+        let mut bbox = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for info in infos.iter() {
+            bbox = bbox.union(&info.bbox);
+            centroid_bounds = centroid_bounds.grow(info.centroid);
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bbox: bbox,
+            offset: 0,
+            count: 0,
+        });
+
+        let split = if infos.len() <= MAX_LEAF_PRIMITIVES {
+            None
+        } else if depth + ceil_log2(infos.len()) + 1 >= MAX_TRAVERSAL_DEPTH {
+            // SAH can build arbitrarily deep trees on skewed inputs. Median splits from here on
+            // finish within ceil(log2(n)) levels, keeping leaves inside the traversal stack.
+            Some(Bvh::median_split(infos, &centroid_bounds))
+        } else {
+            Bvh::find_split(infos, &bbox, &centroid_bounds)
+        };
+
+        match split {
+            Some(mid) => {
+                let (left, right) = infos.split_at_mut(mid);
+                self.build_recursive(left, depth + 1);
+                let second_child = self.build_recursive(right, depth + 1);
+                self.nodes[mut][node_index].offset = second_child;
+            },
+            None => {
+                let node = &mut self.nodes[mut][node_index];
+                node.offset = self.prim_indices.len();
+                node.count = infos.len();
+                for info in infos.iter() {
+                    self.prim_indices.push(info.index);
+                }
+            },
+        }
+
+        node_index
+    }
+
+    /// Partitions `infos` into two halves along the largest centroid extent, returning the
+    /// partition point.
+    fn median_split(infos: &mut [PrimInfo], centroid_bounds: &Aabb) -> uint {
+        let axis = largest_axis(centroid_bounds.extent());
+        infos.sort_by(|a, b| {
+            a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap_or(Ordering::Equal)
+        });
+        infos.len() / 2
+    }
+
+    /// Partitions `infos` along the cheapest SAH split, returning the partition point, or `None`
+    /// if a leaf is cheaper.
+    fn find_split(infos: &mut [PrimInfo], bbox: &Aabb,
+                  centroid_bounds: &Aabb) -> Option<uint> {
+        let extent = centroid_bounds.extent();
+        let axis = largest_axis(extent);
+
+        if extent[axis] <= 0.0 {
+            // All centroids coincide, just split in the middle
+            let mid = infos.len() / 2;
+            return if infos.len() > 2 * MAX_LEAF_PRIMITIVES { Some(mid) } else { None };
+        }
+
+        let bin_scale = NUM_SAH_BINS as f32 / extent[axis];
+        let bin_of = |centroid: Vec3f| -> uint {
+            let bin = ((centroid[axis] - centroid_bounds.min[axis]) * bin_scale) as uint;
+            if bin < NUM_SAH_BINS { bin } else { NUM_SAH_BINS - 1 }
+        };
+
+        let mut bin_count = [0u, ..NUM_SAH_BINS];
+        let mut bin_bbox = [Aabb::empty(), ..NUM_SAH_BINS];
+        for info in infos.iter() {
+            let b = bin_of(info.centroid);
+            bin_count[b] += 1;
+            bin_bbox[b] = bin_bbox[b].union(&info.bbox);
+        }
+
+        // Cost of splitting after each bin, sweeping from the right
+        let mut right_cost = [0.0f32, ..NUM_SAH_BINS];
+        let mut acc_bbox = Aabb::empty();
+        let mut acc_count = 0u;
+        for b in range(1, NUM_SAH_BINS).rev() {
+            acc_bbox = acc_bbox.union(&bin_bbox[b]);
+            acc_count += bin_count[b];
+            right_cost[b - 1] = acc_count as f32 * acc_bbox.surface_area();
+        }
+
+        let mut best_bin = 0u;
+        let mut best_cost = 1e36f32;
+        let mut acc_bbox = Aabb::empty();
+        let mut acc_count = 0u;
+        for b in range(0, NUM_SAH_BINS - 1) {
+            acc_bbox = acc_bbox.union(&bin_bbox[b]);
+            acc_count += bin_count[b];
+            let cost = acc_count as f32 * acc_bbox.surface_area() + right_cost[b];
+            if cost < best_cost {
+                best_cost = cost;
+                best_bin = b;
+            }
+        }
+
+        // Traversal is assumed to cost as much as one intersection test
+        let leaf_cost = infos.len() as f32;
+        let split_cost = 1.0 + best_cost / bbox.surface_area();
+        if split_cost >= leaf_cost && infos.len() <= 2 * MAX_LEAF_PRIMITIVES {
+            return None;
+        }
+
+        let mut mid = 0;
+        for i in range(0, infos.len()) {
+            if bin_of(infos[i].centroid) <= best_bin {
+                infos.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        if mid == 0 || mid == infos.len() {
+            mid = infos.len() / 2;
+        }
+
+        Some(mid)
+    }
+
+    pub fn bbox(&self) -> Aabb {
+        if self.nodes.is_empty() {
+            Aabb::empty()
+        } else {
+            self.nodes[0].bbox
+        }
+    }
+
+    pub fn intersect<P: BvhPrimitives>(&self, primitives: &P, ray: &Ray,
+                                       result: &mut Isect) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = vec3s(1.0) / ray.dir;
+
+        let mut stack = [0u, ..MAX_TRAVERSAL_DEPTH];
+        let mut stack_size = 1u;
+        let mut any_intersection = false;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_index = stack[stack_size];
+            let node = &self.nodes[node_index];
+
+            // The upper bound shrinks as closer intersections are found
+            if !node.bbox.intersect_ray(ray.org, inv_dir, ray.tmin, result.dist) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &prim in self.prim_indices[node.offset..node.offset + node.count].iter() {
+                    any_intersection |= primitives.intersect_primitive(prim, ray, result);
+                }
+            } else {
+                stack[stack_size] = node.offset;
+                stack[stack_size + 1] = node_index + 1;
+                stack_size += 2;
+            }
+        }
+
+        any_intersection
+    }
+
+    pub fn occluded<P: BvhPrimitives>(&self, primitives: &P, ray: &Ray) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = vec3s(1.0) / ray.dir;
+
+        let mut stack = [0u, ..MAX_TRAVERSAL_DEPTH];
+        let mut stack_size = 1u;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_index = stack[stack_size];
+            let node = &self.nodes[node_index];
+
+            if !node.bbox.intersect_ray(ray.org, inv_dir, ray.tmin, ray.tmax) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &prim in self.prim_indices[node.offset..node.offset + node.count].iter() {
+                    if primitives.occluded_primitive(prim, ray) {
+                        return true;
+                    }
+                }
+            } else {
+                stack[stack_size] = node.offset;
+                stack[stack_size + 1] = node_index + 1;
+                stack_size += 2;
+            }
+        }
+
+        false
+    }
+}
+
+fn largest_axis(extent: Vec3f) -> uint {
+    if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    }
+}
+
+/// Smallest `levels` with `2^levels >= n`.
+fn ceil_log2(n: uint) -> uint {
+    let mut levels = 0u;
+    while (1u << levels) < n {
+        levels += 1;
+    }
+    levels
+}
@@ -1,63 +1,674 @@
-use std::num::FloatMath;
-use math::{Vec3f, Vec2f, Mat4f, vec3, PI};
+use std::num::{Float, FloatMath};
+use math::{Vec3f, Vec2f, Mat4f, vec2, vec2s, vec3, vec3s, sqr, PI};
+use utils::{sample_concentric_disc, concentric_disc_pdf_a};
 use ray::Ray;
+use std::path::Path;
+use std::io::File;
 
-pub struct Camera {
-    position: Vec3f,
-    forward: Vec3f,
-    pub resolution: Vec2f,
-    raster_to_world_mat: Mat4f,
-    world_to_raster_mat: Mat4f,
+/// Position and orientation of the camera at one instant.
+#[deriving(Copy, Clone)]
+pub struct CameraPose {
+    pub position: Vec3f,
+    pub forward: Vec3f,
+    pub up: Vec3f,
+}
+
+impl CameraPose {
+    pub fn new(position: Vec3f, forward: Vec3f, up: Vec3f) -> CameraPose {
+        CameraPose {
+            position: position,
+            forward: forward.normalized(),
+            up: up.normalized(),
+        }
+    }
+
+    /// Pose at `eye` looking at `target`. `up` only needs to be roughly perpendicular to the
+    /// viewing direction.
+    pub fn look_at(eye: Vec3f, target: Vec3f, up: Vec3f) -> CameraPose {
+        CameraPose::new(eye, target - eye, up)
+    }
+
+    /// Linear interpolation. The axes are re-orthonormalized by `camera_to_world`.
+    fn lerp(&self, o: &CameraPose, t: f32) -> CameraPose {
+        let s = vec3s(1.0 - t);
+        let t = vec3s(t);
+        CameraPose::new(self.position * s + o.position * t,
+                        self.forward * s + o.forward * t,
+                        self.up * s + o.up * t)
+    }
+
+    /// Camera space has x pointing right, y up and the camera looking down -z.
+    fn camera_to_world(&self) -> Mat4f {
+        let forward = self.forward.normalized();
+        let right = self.up.cross(-forward).normalized();
+        let up = (-forward).cross(right);
+
+        let mut res = Mat4f::identity();
+        for r in range(0, 3) {
+            res[(r, 0)] = right[r];
+            res[(r, 1)] = up[r];
+            res[(r, 2)] = -forward[r];
+            res[(r, 3)] = self.position[r];
+        }
+        res
+    }
+}
+
+/// Camera poses over the shutter interval. The camera moves linearly from the start to the end
+/// pose, if there is one.
+pub struct CameraMotion {
+    start: CameraPose,
+    end: Option<CameraPose>,
+    /// Transforms of the start pose.
+    camera_to_world_mat: Mat4f,
+    world_to_camera_mat: Mat4f,
+}
+
+impl CameraMotion {
+    pub fn new(start: CameraPose, end: Option<CameraPose>) -> CameraMotion {
+        let camera_to_world = start.camera_to_world();
+        CameraMotion {
+            start: start,
+            end: end,
+            camera_to_world_mat: camera_to_world,
+            world_to_camera_mat: camera_to_world.inverted(),
+        }
+    }
+
+    pub fn fixed(pose: CameraPose) -> CameraMotion {
+        CameraMotion::new(pose, None)
+    }
+
+    /// Pose at `time`, in [0, 1] over the shutter interval.
+    pub fn pose_at(&self, time: f32) -> CameraPose {
+        match self.end {
+            Some(ref end) => self.start.lerp(end, time),
+            None => self.start,
+        }
+    }
+
+    fn camera_to_world(&self, time: f32) -> Mat4f {
+        match self.end {
+            Some(_) => self.pose_at(time).camera_to_world(),
+            None => self.camera_to_world_mat,
+        }
+    }
+
+    fn world_to_camera(&self, time: f32) -> Mat4f {
+        match self.end {
+            Some(_) => self.pose_at(time).camera_to_world().inverted(),
+            None => self.world_to_camera_mat,
+        }
+    }
+}
+
+/// Connection from a scene point to the camera, used by light-driven algorithms.
+pub struct CameraConnection {
+    /// Raster position the point is imaged at.
+    pub raster: Vec2f,
+    /// Point on the lens or sensor the point connects to.
+    pub position: Vec3f,
+    /// Pdf of the camera generating a ray that ends at the point, per pixel and with respect to
+    /// area at the point. Excludes the cosine at the point, which depends on its surface.
+    pub pdf_a: f32,
+}
+
+pub trait AbstractCamera {
+    fn resolution(&self) -> Vec2f;
+
+    fn pose_at(&self, time: f32) -> CameraPose;
+
+    /// Generates a ray through `raster_xy` at `time`, in [0, 1] over the shutter interval.
+    /// `lens_sample` picks the point on the lens, and is ignored by cameras without one.
+    fn generate_ray(&self, raster_xy: Vec2f, lens_sample: Vec2f, time: f32) -> Ray;
+
+    /// Finds where the camera sees `p`. Returns `None` if it doesn't.
+    fn connect(&self, p: Vec3f, lens_sample: Vec2f, time: f32) -> Option<CameraConnection>;
+}
+
+fn inside_raster(raster: Vec2f, resolution: Vec2f) -> bool {
+    raster.x >= 0.0 && raster.x < resolution.x && raster.y >= 0.0 && raster.y < resolution.y
+}
+
+/// Describes why a look-at pose has no well-defined orientation, if it doesn't.
+fn degenerate_pose(eye: Vec3f, target: Vec3f, up: Vec3f) -> Option<&'static str> {
+    let forward = target - eye;
+    if forward.length_sqr() == 0.0 {
+        Some("The eye and target coincide.")
+    } else if up.length_sqr() == 0.0 ||
+              up.normalized().cross(forward.normalized()).length() < 1e-4 {
+        Some("The up vector is zero or parallel to the view direction.")
+    } else {
+        None
+    }
+}
+
+#[deriving(Copy)]
+pub enum CameraModel {
+    Perspective,
+    Orthographic,
+    Equirectangular,
+}
+
+impl CameraModel {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            CameraModel::Perspective => "persp",
+            CameraModel::Orthographic => "ortho",
+            CameraModel::Equirectangular => "equirect",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<CameraModel> {
+        Some(match s {
+            "persp"    => CameraModel::Perspective,
+            "ortho"    => CameraModel::Orthographic,
+            "equirect" => CameraModel::Equirectangular,
+            _ => return None,
+        })
+    }
+}
+
+/// Field of view in degrees, along either image axis.
+#[deriving(Copy)]
+pub enum Fov {
+    Horizontal(f32),
+    Vertical(f32),
+}
+
+impl Fov {
+    /// Distance to the image plane where pixels have unit size.
+    pub fn image_plane_dist(self, resolution: Vec2f) -> f32 {
+        let (fov, size) = match self {
+            Fov::Horizontal(fov) => (fov, resolution.x),
+            Fov::Vertical(fov) => (fov, resolution.y),
+        };
+        size / (2.0 * (fov * PI / 360.0).tan())
+    }
+}
+
+/// Placement of the camera in the scene.
+#[deriving(Copy)]
+pub struct CameraView {
+    pub start: CameraPose,
+    /// Pose at shutter close, if the camera moves.
+    pub end: Option<CameraPose>,
+    pub fov: Fov,
+}
+
+impl CameraView {
+    pub fn new(pose: CameraPose, fov: Fov) -> CameraView {
+        CameraView { start: pose, end: None, fov: fov }
+    }
+}
+
+/// Camera model and lens chosen independently of the scene, which provides the view.
+#[deriving(Copy)]
+pub struct CameraSettings {
+    pub model: CameraModel,
+    /// Zero for a pinhole camera.
+    pub aperture_radius: f32,
+    /// Defaults to the distance to the scene center.
+    pub focus_distance: Option<f32>,
+    /// Replaces the view of the scene, if set.
+    pub view: Option<CameraView>,
+}
+
+impl CameraSettings {
+    pub fn new() -> CameraSettings {
+        CameraSettings {
+            model: CameraModel::Perspective,
+            aperture_radius: 0.0,
+            focus_distance: None,
+            view: None,
+        }
+    }
+
+    /// Reads a view from a text file with one `keyword values...` entry per line, where `#`
+    /// starts a comment:
+    ///
+    /// ```text
+    /// eye x y z
+    /// target x y z
+    /// up x y z          # optional, defaults to 0 0 1
+    /// hfov degrees      # or vfov
+    /// end_eye x y z     # optional end of shutter pose, also end_target and end_up
+    /// aperture radius   # optional
+    /// focus distance    # optional
+    /// ```
+    pub fn load_camera_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = match File::open(path).read_to_string() {
+            Ok(text) => text,
+            Err(e) => return Err(format!(
+                "Could not read camera file \"{}\": {}", path.display(), e)),
+        };
+
+        let mut eye = None;
+        let mut target = None;
+        let mut up = vec3(0.0, 0.0, 1.0);
+        let mut fov = None;
+        let mut end_eye = None;
+        let mut end_target = None;
+        let mut end_up = None;
+        // Last lines defining each pose, for reporting degenerate ones
+        let mut pose_line = 0;
+        let mut end_pose_line = 0;
+
+        for (line_index, line) in text[].lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let words: Vec<&str> = line.words().collect();
+            let mut values = Vec::with_capacity(words.len() - 1);
+            for word in words[1..].iter() {
+                match from_str::<f32>(*word) {
+                    Some(value) => values.push(value),
+                    None => return Err(format!("{}:{}: Invalid number \"{}\".",
+                                               path.display(), line_index + 1, word)),
+                }
+            }
+
+            let num_values = match words[0] {
+                "eye" | "target" | "up" | "end_eye" | "end_target" | "end_up" => 3,
+                "hfov" | "vfov" | "aperture" | "focus" => 1,
+                keyword => return Err(format!("{}:{}: Unknown keyword \"{}\".",
+                                              path.display(), line_index + 1, keyword)),
+            };
+            if values.len() != num_values {
+                return Err(format!("{}:{}: \"{}\" expects {} values.",
+                                   path.display(), line_index + 1, words[0], num_values));
+            }
+
+            let invalid = match words[0] {
+                "hfov" | "vfov" => values[0] <= 0.0 || values[0] >= 180.0,
+                "aperture" => values[0] < 0.0,
+                "focus" => values[0] <= 0.0,
+                _ => false,
+            };
+            if invalid {
+                return Err(format!("{}:{}: Invalid {} {}.",
+                                   path.display(), line_index + 1, words[0], values[0]));
+            }
+
+            match words[0] {
+                "eye" | "target" | "up" => pose_line = line_index + 1,
+                "end_eye" | "end_target" | "end_up" => end_pose_line = line_index + 1,
+                _ => (),
+            }
+
+            let v = if num_values == 3 { vec3(values[0], values[1], values[2]) } else { vec3s(0.0) };
+            match words[0] {
+                "eye" => eye = Some(v),
+                "target" => target = Some(v),
+                "up" => up = v,
+                "end_eye" => end_eye = Some(v),
+                "end_target" => end_target = Some(v),
+                "end_up" => end_up = Some(v),
+                "hfov" => fov = Some(Fov::Horizontal(values[0])),
+                "vfov" => fov = Some(Fov::Vertical(values[0])),
+                "aperture" => self.aperture_radius = values[0],
+                "focus" => self.focus_distance = Some(values[0]),
+                _ => unreachable!(),
+            }
+        }
+
+        let (eye, target, fov) = match (eye, target, fov) {
+            (Some(eye), Some(target), Some(fov)) => (eye, target, fov),
+            _ => return Err(format!(
+                "Camera file \"{}\" needs an eye, a target and a field of view.", path.display())),
+        };
+
+        match degenerate_pose(eye, target, up) {
+            Some(error) => return Err(format!("{}:{}: {}", path.display(), pose_line, error)),
+            None => (),
+        }
+
+        let end = if end_eye.is_some() || end_target.is_some() || end_up.is_some() {
+            let (end_eye, end_target, end_up) =
+                (end_eye.unwrap_or(eye), end_target.unwrap_or(target), end_up.unwrap_or(up));
+            match degenerate_pose(end_eye, end_target, end_up) {
+                Some(error) => return Err(format!("{}:{}: {}",
+                                                  path.display(), end_pose_line, error)),
+                None => (),
+            }
+            Some(CameraPose::look_at(end_eye, end_target, end_up))
+        } else {
+            None
+        };
+
+        self.view = Some(CameraView {
+            start: CameraPose::look_at(eye, target, up),
+            end: end,
+            fov: fov,
+        });
+        Ok(())
+    }
+
+    /// Creates a camera for the scene's view, unless overridden. Orthographic cameras cover the
+    /// same area as the perspective view does at the focus distance.
+    pub fn create(&self, scene_view: &CameraView, resolution: Vec2f,
+                  scene_center: Vec3f) -> Box<AbstractCamera + Sync + Send + 'static> {
+        let view = match self.view {
+            Some(ref view) => view,
+            None => scene_view,
+        };
+
+        let focus_distance = match self.focus_distance {
+            Some(focus_distance) => focus_distance,
+            None => (scene_center - view.start.position).dot(view.start.forward),
+        };
+
+        let motion = CameraMotion::new(view.start, view.end);
+        match self.model {
+            CameraModel::Perspective => {
+                let mut camera = PerspectiveCamera::new(motion, resolution, view.fov);
+                if self.aperture_radius > 0.0 {
+                    camera.set_lens(self.aperture_radius, focus_distance);
+                }
+                box camera
+            },
+            CameraModel::Orthographic => {
+                let width = focus_distance * resolution.x / view.fov.image_plane_dist(resolution);
+                box OrthographicCamera::new(motion, resolution, width)
+            },
+            CameraModel::Equirectangular => box EquirectangularCamera::new(motion, resolution),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Perspective camera
+
+pub struct PerspectiveCamera {
+    motion: CameraMotion,
+    resolution: Vec2f,
+    /// Radius of the thin lens. Zero for a pinhole camera.
+    aperture_radius: f32,
+    /// Distance along the viewing direction of the plane in focus.
+    focus_distance: f32,
+    /// Distance to an image plane where pixels have unit area.
     image_plane_dist: f32,
 }
 
-impl Camera {
-    pub fn new(position: Vec3f, forward: Vec3f, up: Vec3f,
-               resolution: Vec2f, horizontal_fov: f32) -> Camera {
-        let forward = forward.normalized();
-        let up = up.cross(-forward).normalized();
-        let left = (-forward).cross(up);
+impl PerspectiveCamera {
+    /// Pixels are square, so the field of view along the other axis follows from the aspect
+    /// ratio of `resolution`.
+    pub fn new(motion: CameraMotion, resolution: Vec2f, fov: Fov) -> PerspectiveCamera {
+        PerspectiveCamera {
+            motion: motion,
+            resolution: resolution,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            image_plane_dist: fov.image_plane_dist(resolution),
+        }
+    }
+
+    pub fn look_at(eye: Vec3f, target: Vec3f, up: Vec3f, resolution: Vec2f,
+                   fov: Fov) -> PerspectiveCamera {
+        PerspectiveCamera::new(CameraMotion::fixed(CameraPose::look_at(eye, target, up)),
+                               resolution, fov)
+    }
+
+    /// Direction through a point on the image plane, in camera space.
+    fn raster_to_camera(&self, raster_xy: Vec2f) -> Vec3f {
+        // Raster y points down
+        let offset = raster_xy - self.resolution * vec2s(0.5);
+        vec3(offset.x, -offset.y, -self.image_plane_dist)
+    }
+
+    /// Projects a camera space point onto the image plane. The point must be in front.
+    fn camera_to_raster(&self, p: Vec3f) -> Vec2f {
+        let scale = self.image_plane_dist / -p.z;
+        vec2(p.x * scale, -p.y * scale) + self.resolution * vec2s(0.5)
+    }
+
+    /// Turns the camera into a thin lens camera. Points at `focus_distance` along the viewing
+    /// direction are in focus.
+    pub fn set_lens(&mut self, aperture_radius: f32, focus_distance: f32) {
+        self.aperture_radius = aperture_radius;
+        self.focus_distance = focus_distance;
+    }
+
+    pub fn is_pinhole(&self) -> bool {
+        self.aperture_radius <= 0.0
+    }
+
+    /// Samples a point on the lens. Returns the point and its pdf with respect to lens area,
+    /// which is 1 for the (delta) pinhole.
+    pub fn sample_lens(&self, lens_sample: Vec2f, time: f32) -> (Vec3f, f32) {
+        if self.is_pinhole() {
+            return (self.motion.pose_at(time).position, 1.0);
+        }
+
+        let lens = sample_concentric_disc(lens_sample) * vec2s(self.aperture_radius);
+        let lens_point = self.motion.camera_to_world(time)
+            .transform_point(&vec3(lens.x, lens.y, 0.0));
+        (lens_point, concentric_disc_pdf_a() / sqr(self.aperture_radius))
+    }
+
+    /// Finds the raster position `p` is imaged at when seen through `lens_point`, as returned by
+    /// `sample_lens`. Returns `None` if `p` is behind the camera or outside the image.
+    pub fn raster_position(&self, p: Vec3f, lens_point: Vec3f, time: f32) -> Option<Vec2f> {
+        let world_to_camera = self.motion.world_to_camera(time);
+        let p_camera = world_to_camera.transform_point(&p);
+        let lens_camera = world_to_camera.transform_point(&lens_point);
+
+        let dir = p_camera - lens_camera;
+        if dir.z >= 0.0 {
+            return None;
+        }
+
+        // All rays through the same point on the focus plane end up on the same pixel
+        let focus_point = lens_camera + dir * vec3s(self.focus_distance / -dir.z);
+        let raster = self.camera_to_raster(focus_point);
+
+        if inside_raster(raster, self.resolution) { Some(raster) } else { None }
+    }
+
+    /// Solid angle pdf of generating a camera ray leaving at an angle with cosine
+    /// `cos_at_camera` to the viewing direction, per pixel. For thin lens cameras this is
+    /// conditional on the point on the lens.
+    pub fn pdf_w(&self, cos_at_camera: f32) -> f32 {
+        let image_point_to_camera_dist = self.image_plane_dist / cos_at_camera;
+        sqr(image_point_to_camera_dist) / cos_at_camera
+    }
+}
+
+impl AbstractCamera for PerspectiveCamera {
+    fn resolution(&self) -> Vec2f {
+        self.resolution
+    }
+
+    fn pose_at(&self, time: f32) -> CameraPose {
+        self.motion.pose_at(time)
+    }
+
+    fn generate_ray(&self, raster_xy: Vec2f, lens_sample: Vec2f, time: f32) -> Ray {
+        let pinhole_dir = self.raster_to_camera(raster_xy).normalized();
+
+        let (org, dir) = if self.is_pinhole() {
+            (vec3s(0.0), pinhole_dir)
+        } else {
+            let lens = sample_concentric_disc(lens_sample) * vec2s(self.aperture_radius);
+            let lens_point = vec3(lens.x, lens.y, 0.0);
+            let focus_point = pinhole_dir * vec3s(self.focus_distance / -pinhole_dir.z);
+            (lens_point, (focus_point - lens_point).normalized())
+        };
 
-        let pos = vec3(up.dot(position),
-                       left.dot(position),
-                       (-forward).dot(position));
+        let camera_to_world = self.motion.camera_to_world(time);
+        Ray {
+            org: camera_to_world.transform_point(&org),
+            dir: camera_to_world.transform_vector(&dir).normalized(),
+            tmin: 0.0,
+            tmax: 1e36,
+            time: time,
+        }
+    }
 
-        let mut world_to_camera = Mat4f::identity();
-        world_to_camera.set_row_vec3(0, up,       -pos.x);
-        world_to_camera.set_row_vec3(1, left,     -pos.y);
-        world_to_camera.set_row_vec3(2, -forward, -pos.z);
+    fn connect(&self, p: Vec3f, lens_sample: Vec2f, time: f32) -> Option<CameraConnection> {
+        // The lens pdf cancels out with the lens area in the importance, so only the
+        // directional part is needed
+        let (lens_point, _) = self.sample_lens(lens_sample, time);
+        let raster = match self.raster_position(p, lens_point, time) {
+            Some(raster) => raster,
+            None => return None,
+        };
 
-        let perspective = Mat4f::perspective(horizontal_fov, 0.1, 10000.0);
-        let world_to_nscreen = perspective * world_to_camera;
-        let nscreen_to_world = world_to_nscreen.inverted();
+        let to_point = p - lens_point;
+        let dist_sqr = to_point.length_sqr();
+        let cos_at_camera = to_point.dot(self.motion.pose_at(time).forward) / dist_sqr.sqrt();
 
-        let tan_half_angle = (horizontal_fov * PI / 360.0).tan();
+        Some(CameraConnection {
+            raster: raster,
+            position: lens_point,
+            pdf_a: self.pdf_w(cos_at_camera) / dist_sqr,
+        })
+    }
+}
 
-        Camera {
+//////////////////////////////////////////////////////////////////////////
+// Orthographic camera
+
+/// Parallel projection of a `width` wide rectangle around the camera position.
+pub struct OrthographicCamera {
+    motion: CameraMotion,
+    resolution: Vec2f,
+    /// World space edge length of a (square) pixel.
+    pixel_size: f32,
+}
+
+impl OrthographicCamera {
+    pub fn new(motion: CameraMotion, resolution: Vec2f, width: f32) -> OrthographicCamera {
+        OrthographicCamera {
+            motion: motion,
+            resolution: resolution,
+            pixel_size: width / resolution.x,
+        }
+    }
+}
+
+impl AbstractCamera for OrthographicCamera {
+    fn resolution(&self) -> Vec2f {
+        self.resolution
+    }
+
+    fn pose_at(&self, time: f32) -> CameraPose {
+        self.motion.pose_at(time)
+    }
+
+    fn generate_ray(&self, raster_xy: Vec2f, _lens_sample: Vec2f, time: f32) -> Ray {
+        // Raster y points down
+        let offset = (raster_xy - self.resolution * vec2s(0.5)) * vec2s(self.pixel_size);
+        let org = vec3(offset.x, -offset.y, 0.0);
+
+        let camera_to_world = self.motion.camera_to_world(time);
+        Ray {
+            org: camera_to_world.transform_point(&org),
+            dir: camera_to_world.transform_vector(&vec3(0.0, 0.0, -1.0)).normalized(),
+            tmin: 0.0,
+            tmax: 1e36,
+            time: time,
+        }
+    }
+
+    fn connect(&self, p: Vec3f, _lens_sample: Vec2f, time: f32) -> Option<CameraConnection> {
+        let p_camera = self.motion.world_to_camera(time).transform_point(&p);
+        if p_camera.z >= 0.0 {
+            return None;
+        }
+
+        let raster = vec2(p_camera.x, -p_camera.y) / vec2s(self.pixel_size) +
+                     self.resolution * vec2s(0.5);
+        if !inside_raster(raster, self.resolution) {
+            return None;
+        }
+
+        let position = self.motion.camera_to_world(time)
+            .transform_point(&vec3(p_camera.x, p_camera.y, 0.0));
+
+        Some(CameraConnection {
+            raster: raster,
             position: position,
-            forward: forward,
+            pdf_a: 1.0 / sqr(self.pixel_size),
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Equirectangular camera
+
+/// 360 degree panorama in latitude-longitude layout, with the viewing direction in the center
+/// and up at the top of the image.
+pub struct EquirectangularCamera {
+    motion: CameraMotion,
+    resolution: Vec2f,
+}
+
+impl EquirectangularCamera {
+    pub fn new(motion: CameraMotion, resolution: Vec2f) -> EquirectangularCamera {
+        EquirectangularCamera {
+            motion: motion,
             resolution: resolution,
-            raster_to_world_mat: nscreen_to_world *
-                                 Mat4f::translate(&vec3(-1.0, -1.0, 0.0)) *
-                                 Mat4f::scale(&vec3(2.0 / resolution.x, 2.0 / resolution.y, 0.0)),
-            world_to_raster_mat: Mat4f::scale(&vec3(resolution.x * 0.5, resolution.y * 0.5, 0.0)) *
-                                 Mat4f::translate(&vec3(1.0, 1.0, 0.0)) *
-                                 world_to_nscreen,
-            image_plane_dist: resolution.x / (2.0 * tan_half_angle),
         }
     }
 
-    fn raster_to_world(&self, raster_xy: Vec2f) -> Vec3f {
-        self.raster_to_world_mat.transform_point(&vec3(raster_xy.x, raster_xy.y, 0.0))
+    /// Solid angle pdf of generating a camera ray with polar angle sine `sin_theta`, per pixel.
+    fn pdf_w(&self, sin_theta: f32) -> f32 {
+        self.resolution.x * self.resolution.y / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl AbstractCamera for EquirectangularCamera {
+    fn resolution(&self) -> Vec2f {
+        self.resolution
+    }
+
+    fn pose_at(&self, time: f32) -> CameraPose {
+        self.motion.pose_at(time)
     }
 
-    pub fn generate_ray(&self, raster_xy: Vec2f) -> Ray {
-        let world_raster = self.raster_to_world(raster_xy);
+    fn generate_ray(&self, raster_xy: Vec2f, _lens_sample: Vec2f, time: f32) -> Ray {
+        let phi = (raster_xy.x / self.resolution.x - 0.5) * 2.0 * PI;
+        let theta = raster_xy.y / self.resolution.y * PI;
+
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let dir = vec3(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi);
 
+        let camera_to_world = self.motion.camera_to_world(time);
         Ray {
-            org: self.position,
-            dir: (world_raster - self.position).normalized(),
-            tmin: 0.0
+            org: self.motion.pose_at(time).position,
+            dir: camera_to_world.transform_vector(&dir).normalized(),
+            tmin: 0.0,
+            tmax: 1e36,
+            time: time,
+        }
+    }
+
+    fn connect(&self, p: Vec3f, _lens_sample: Vec2f, time: f32) -> Option<CameraConnection> {
+        let to_point = self.motion.world_to_camera(time).transform_point(&p);
+        let dist_sqr = to_point.length_sqr();
+        let dir = to_point / vec3s(dist_sqr.sqrt());
+
+        let sin_theta = (1.0 - sqr(dir.y)).max(0.0).sqrt();
+        if sin_theta == 0.0 {
+            return None;
         }
+
+        let theta = dir.y.max(-1.0).min(1.0).acos();
+        let phi = dir.x.atan2(-dir.z);
+        let raster = vec2((phi / (2.0 * PI) + 0.5) * self.resolution.x,
+                          theta / PI * self.resolution.y);
+        if !inside_raster(raster, self.resolution) {
+            return None;
+        }
+
+        Some(CameraConnection {
+            raster: raster,
+            position: self.motion.pose_at(time).position,
+            pdf_a: self.pdf_w(sin_theta) / dist_sqr,
+        })
     }
 }
@@ -1,13 +1,22 @@
 use math::Vec2i;
-use math::vec2;
+use math::{vec2, vec3};
 use std::default::Default;
 use scene;
 use scene::{BoxMask, Scene};
 use renderer::AbstractRenderer;
-use framebuffer::Framebuffer;
+use framebuffer::{Framebuffer, CropWindow};
+use convergence::ConvergenceLog;
+use aov::Aov;
+use camera::{CameraSettings, CameraModel};
+use filter::Filter;
+use lights::{EnvironmentLight, SunSkyLight, LightSelection, MIN_SPOT_ANGLE};
+use ies::IesProfile;
+use std::path::Path;
 
 enum Algorithm {
     EyeLight,
+    DebugAov,
+    AmbientOcclusion,
     PathTracing,
     LightTracing,
     ProgressivePhotonMapping,
@@ -20,6 +29,8 @@ impl Algorithm {
     pub fn get_name(self) -> &'static str {
         match self {
             Algorithm::EyeLight => "eye light",
+            Algorithm::DebugAov => "AOV debug",
+            Algorithm::AmbientOcclusion => "ambient occlusion",
             Algorithm::PathTracing => "path tracing",
             Algorithm::LightTracing => "light tracing",
             Algorithm::ProgressivePhotonMapping => "progressive photon mapping",
@@ -32,6 +43,8 @@ impl Algorithm {
     fn get_acronym(self) -> &'static str {
         match self {
             Algorithm::EyeLight => "el",
+            Algorithm::DebugAov => "aov",
+            Algorithm::AmbientOcclusion => "ao",
             Algorithm::PathTracing => "pt",
             Algorithm::LightTracing => "lt",
             Algorithm::ProgressivePhotonMapping => "ppm",
@@ -44,6 +57,8 @@ impl Algorithm {
     fn from_acronym(s: &str) -> Option<Algorithm> {
         Some(match s {
             "el"  => Algorithm::EyeLight,
+            "aov" => Algorithm::DebugAov,
+            "ao"  => Algorithm::AmbientOcclusion,
             "pt"  => Algorithm::PathTracing,
             "lt"  => Algorithm::LightTracing,
             "ppm" => Algorithm::ProgressivePhotonMapping,
@@ -73,7 +88,19 @@ pub struct Config {
     pub min_path_length: u32,
     pub output_name: String,
     resolution: Vec2i,
+    camera: CameraSettings,
+    pub filter: Filter,
+    pub crop: Option<CropWindow>,
+    /// Whether to output a cropped render inside the full frame rather than on its own.
+    pub crop_embed: bool,
     pub full_report: bool,
+    pub aov: Aov,
+    /// Length of ambient occlusion rays. Defaults to the scene radius.
+    pub ao_max_distance: Option<f32>,
+    pub convergence: Option<ConvergenceLog>,
+    /// In the units of `run_limit`. If `None` error is only recorded at the end.
+    pub convergence_interval: Option<f64>,
+    pub convergence_csv: String,
 }
 
 impl Default for Config {
@@ -92,7 +119,16 @@ impl Default for Config {
             output_name: "".to_string(),
             // Explicit literal type is a workaround for rust#18954
             resolution: vec2(512i32, 512),
+            camera: CameraSettings::new(),
+            filter: Filter::Box,
+            crop: None,
+            crop_embed: false,
             full_report: false,
+            aov: Aov::ShadingNormal,
+            ao_max_distance: None,
+            convergence: None,
+            convergence_interval: None,
+            convergence_csv: "".to_string(),
         }
     }
 }
@@ -102,6 +138,12 @@ pub fn create_renderer<'a, 'b>(config: &'a Config, seed: u32) -> Box<AbstractRen
 
     match config.algorithm {
         Algorithm::EyeLight => box ::eyelight::EyeLight::new(scene, seed),
+        Algorithm::DebugAov => box ::aov::AovRenderer::new(scene, seed, config.aov),
+        Algorithm::AmbientOcclusion => {
+            let max_distance = config.ao_max_distance
+                .unwrap_or(scene.scene_sphere().scene_radius);
+            box ::ambientocclusion::AmbientOcclusion::new(scene, seed, max_distance)
+        },
         // TODO
         _ => unimplemented!()
     }
@@ -148,9 +190,31 @@ pub fn parse_commandline(argv: &[String]) -> Result<Config, String> {
         optflag("", "report", "Renders all scenes using all algorithms and generates an index.html file."),
         optopt("s", "", "Selects the scene.", "sceneID"),
         optopt("a", "", "Selects the rendering algorithm.", "algorithm"),
+        optopt("", "aov", "Output of the AOV debug renderer (-a aov): normal, depth, matid, lightid, albedo or bsdfdir.", "aov"),
+        optopt("", "ao-dist", "Maximum occluder distance for ambient occlusion (-a ao).", "distance"),
+        optopt("r", "res", "Image resolution. Defaults to 512x512.", "WxH"),
+        optopt("", "filter", "Pixel reconstruction filter: box, tent, gaussian, mitchell or blackman-harris.", "filter"),
+        optopt("", "crop", "Only renders pixels from x0,y0 inclusive to x1,y1 exclusive.", "x0,y0,x1,y1"),
+        optflag("", "crop-embed", "Outputs a cropped render inside the full frame, black elsewhere."),
+        optopt("", "camera", "Camera model: persp, ortho or equirect.", "model"),
+        optopt("", "camera-file", "Text file with the camera view, replacing the scene's.", "file"),
+        optopt("", "aperture", "Lens radius for depth of field with the persp camera. Defaults to 0, a pinhole camera.", "radius"),
+        optopt("", "focus", "Distance to the plane in focus, which also sizes the ortho view. Defaults to the scene center.", "distance"),
+        optopt("", "envmap", "Equirectangular environment map (.hdr, .pfm or .exr) replacing the background light.", "file"),
+        optopt("", "envmap-rotation", "Rotation of the environment map around the up axis, in degrees.", "degrees"),
+        optopt("", "envmap-scale", "Multiplier for the environment map radiance. Defaults to 1.", "scale"),
+        optopt("", "sky", "Preetham sun and sky with the given turbidity (2 to 10) replacing the background light and the scene's sun.", "turbidity"),
+        optopt("", "sun", "Direction towards the sun for --sky, with z up. Defaults to 1,-1.5,1.", "x,y,z"),
+        optopt("", "sky-scale", "Multiplier for the sun and sky radiance. Defaults to 1.", "scale"),
+        optopt("", "spot", "Turns the point light (-s 2) into a spot light aimed at the floor, with cone angles in degrees.", "inner,outer"),
+        optopt("", "ies", "IES profile for the point light (-s 2), with the nadir facing the floor.", "file"),
+        optopt("", "light-selection", "How to choose lights to sample: power (default), uniform or bvh.", "mode"),
         optopt("i", "", "Number of iterations to run the algorithm for.", "iterations"),
         optopt("t", "", "Number of seconds to run the algorithm for.", "seconds"),
-        optopt("o", "", "User specified output name, with extension .bmp or .hdr.", "output_name"),
+        optopt("o", "", "User specified output name, with extension .bmp, .hdr or .pfm.", "output_name"),
+        optopt("", "reference", "Reference image (.pfm) to record a convergence curve against.", "file"),
+        optopt("", "conv-interval", "Interval between convergence samples, in iterations or seconds depending on -i/-t.", "interval"),
+        optopt("", "conv-csv", "Output name for the convergence curve. Defaults to the output name with extension .csv.", "file"),
     ];
     let matches = getopts(argv, opts[]).unwrap();
 
@@ -179,6 +243,225 @@ pub fn parse_commandline(argv: &[String]) -> Result<Config, String> {
         None => (),
     }
 
+    match matches.opt_str("aov") {
+        Some(aov_str) => match Aov::from_name(aov_str[]) {
+            Some(aov) => config.aov = aov,
+            _ => return Err(
+                format!("Invalid AOV \"{}\", please see help (-h).", aov_str)),
+        },
+        None => (),
+    }
+
+    match matches.opt_str("ao-dist") {
+        Some(dist_str) => match from_str::<f32>(dist_str[]) {
+            Some(dist) if dist > 0.0 => config.ao_max_distance = Some(dist),
+            _ => return Err(format!(
+                "Invalid ambient occlusion distance \"{}\", please see help (-h).", dist_str)),
+        },
+        None => (),
+    }
+
+    match matches.opt_str("res") {
+        Some(res_str) => {
+            let dims: Vec<Option<i32>> = res_str[].split('x').map(|d| from_str::<i32>(d)).collect();
+            match dims[] {
+                [Some(x), Some(y)] if x > 0 && y > 0 => config.resolution = vec2(x, y),
+                _ => return Err(format!(
+                    "Invalid resolution \"{}\", please see help (-h).", res_str)),
+            }
+        },
+        None => (),
+    }
+
+    match matches.opt_str("filter") {
+        Some(filter_str) => match Filter::from_name(filter_str[]) {
+            Some(filter) => config.filter = filter,
+            _ => return Err(
+                format!("Invalid filter \"{}\", please see help (-h).", filter_str)),
+        },
+        None => (),
+    }
+
+    match matches.opt_str("crop") {
+        Some(crop_str) => {
+            let coords: Vec<Option<i32>> =
+                crop_str[].split(',').map(|c| from_str::<i32>(c.trim())).collect();
+            match coords[] {
+                [Some(x0), Some(y0), Some(x1), Some(y1)]
+                        if 0 <= x0 && x0 < x1 && x1 <= config.resolution.x &&
+                           0 <= y0 && y0 < y1 && y1 <= config.resolution.y =>
+                    config.crop = Some(CropWindow::new(vec2(x0, y0), vec2(x1, y1))),
+                _ => return Err(format!(
+                    "Invalid crop window \"{}\", please see help (-h).", crop_str)),
+            }
+        },
+        None => (),
+    }
+    config.crop_embed = matches.opt_present("crop-embed");
+
+    match matches.opt_str("camera-file") {
+        Some(camera_file) => try!(config.camera.load_camera_file(&Path::new(camera_file[]))),
+        None => (),
+    }
+
+    match matches.opt_str("camera") {
+        Some(model_str) => match CameraModel::from_name(model_str[]) {
+            Some(model) => config.camera.model = model,
+            _ => return Err(
+                format!("Invalid camera model \"{}\", please see help (-h).", model_str)),
+        },
+        None => (),
+    }
+
+    match matches.opt_str("aperture") {
+        Some(aperture_str) => match from_str::<f32>(aperture_str[]) {
+            Some(aperture) if aperture >= 0.0 => config.camera.aperture_radius = aperture,
+            _ => return Err(format!(
+                "Invalid aperture \"{}\", please see help (-h).", aperture_str)),
+        },
+        None => (),
+    }
+
+    match matches.opt_str("focus") {
+        Some(focus_str) => match from_str::<f32>(focus_str[]) {
+            Some(focus) if focus > 0.0 => config.camera.focus_distance = Some(focus),
+            _ => return Err(format!(
+                "Invalid focus distance \"{}\", please see help (-h).", focus_str)),
+        },
+        None => (),
+    }
+
+    // Checked on the final settings, as the aperture and focus may also come from the camera
+    // file. A pinhole camera has everything in focus, so the distance would have no effect.
+    // Orthographic cameras use it to size the view.
+    let has_lens = config.camera.aperture_radius > 0.0;
+    let has_focus = config.camera.focus_distance.is_some();
+    match config.camera.model {
+        CameraModel::Perspective if has_focus && !has_lens =>
+            return Err("A focus distance needs a lens, please set one with --aperture."
+                       .to_string()),
+        CameraModel::Orthographic | CameraModel::Equirectangular if has_lens =>
+            return Err(format!("The {} camera has no lens for an aperture, please see help (-h).",
+                               config.camera.model.get_name())),
+        CameraModel::Equirectangular if has_focus =>
+            return Err("The equirect camera has no focus distance, please see help (-h)."
+                       .to_string()),
+        _ => (),
+    }
+
+    let envmap = match matches.opt_str("envmap") {
+        Some(envmap_name) => {
+            let path = Path::new(envmap_name[]);
+            let image = match path.extension_str() {
+                Some("hdr") => Framebuffer::load_hdr(&path),
+                Some("pfm") => Framebuffer::load_pfm(&path),
+                Some("exr") => Framebuffer::load_exr(&path),
+                _ => return Err(format!(
+                    "Invalid environment map \"{}\", please see help (-h).", envmap_name)),
+            };
+            match image {
+                Ok(image) => Some(image),
+                Err(e) => return Err(format!(
+                    "Could not load environment map \"{}\": {}", envmap_name, e)),
+            }
+        },
+        None => None,
+    };
+
+    let envmap_rotation = match matches.opt_str("envmap-rotation") {
+        Some(rotation_str) => match from_str::<f32>(rotation_str[]) {
+            Some(rotation) => rotation,
+            _ => return Err(format!(
+                "Invalid environment map rotation \"{}\", please see help (-h).", rotation_str)),
+        },
+        None => 0.0,
+    };
+
+    let envmap_scale = match matches.opt_str("envmap-scale") {
+        Some(scale_str) => match from_str::<f32>(scale_str[]) {
+            Some(scale) if scale >= 0.0 => scale,
+            _ => return Err(format!(
+                "Invalid environment map scale \"{}\", please see help (-h).", scale_str)),
+        },
+        None => 1.0,
+    };
+
+    let sky_turbidity = match matches.opt_str("sky") {
+        Some(turbidity_str) => match from_str::<f32>(turbidity_str[]) {
+            Some(turbidity) if turbidity >= 1.0 => Some(turbidity),
+            _ => return Err(format!(
+                "Invalid sky turbidity \"{}\", please see help (-h).", turbidity_str)),
+        },
+        None => None,
+    };
+    if sky_turbidity.is_some() && envmap.is_some() {
+        return Err("Only one of --sky and --envmap can be used.".to_string());
+    }
+
+    let sun_direction = match matches.opt_str("sun") {
+        Some(sun_str) => {
+            let coords: Vec<Option<f32>> =
+                sun_str[].split(',').map(|c| from_str::<f32>(c.trim())).collect();
+            match coords[] {
+                [Some(x), Some(y), Some(z)] if x != 0.0 || y != 0.0 || z != 0.0 =>
+                    vec3(x, y, z),
+                _ => return Err(format!(
+                    "Invalid sun direction \"{}\", please see help (-h).", sun_str)),
+            }
+        },
+        None => vec3(1.0, -1.5, 1.0),
+    };
+
+    let sky_scale = match matches.opt_str("sky-scale") {
+        Some(scale_str) => match from_str::<f32>(scale_str[]) {
+            Some(scale) if scale >= 0.0 => scale,
+            _ => return Err(format!(
+                "Invalid sky scale \"{}\", please see help (-h).", scale_str)),
+        },
+        None => 1.0,
+    };
+
+    let spot_angles = match matches.opt_str("spot") {
+        Some(spot_str) => {
+            let angles: Vec<Option<f32>> =
+                spot_str[].split(',').map(|a| from_str::<f32>(a.trim())).collect();
+            match angles[] {
+                [Some(inner), Some(outer)] if 0.0 <= inner && inner <= outer &&
+                                              MIN_SPOT_ANGLE <= outer && outer <= 180.0 =>
+                    Some((inner, outer)),
+                _ => return Err(format!(
+                    "Invalid spot light angles \"{}\", please see help (-h).", spot_str)),
+            }
+        },
+        None => None,
+    };
+
+    let ies_profile = match matches.opt_str("ies") {
+        Some(ies_name) => match IesProfile::load(&Path::new(ies_name[])) {
+            Ok(profile) => Some(profile),
+            Err(e) => return Err(format!(
+                "Could not load IES profile \"{}\": {}", ies_name, e)),
+        },
+        None => None,
+    };
+    if spot_angles.is_some() && ies_profile.is_some() {
+        return Err("Only one of --spot and --ies can be used.".to_string());
+    }
+    if (spot_angles.is_some() || ies_profile.is_some()) &&
+       !scene_config.contains(scene::LIGHT_POINT) {
+        return Err("--spot and --ies need a scene with a point light, please see help (-h)."
+                   .to_string());
+    }
+
+    let light_selection = match matches.opt_str("light-selection") {
+        Some(selection_str) => match LightSelection::from_name(selection_str[]) {
+            Some(selection) => selection,
+            _ => return Err(format!(
+                "Invalid light selection \"{}\", please see help (-h).", selection_str)),
+        },
+        None => LightSelection::Power,
+    };
+
     match matches.opt_str("i") {
         Some(iterations_str) => match from_str::<uint>(iterations_str[]) {
             Some(iterations) if iterations >= 1 =>
@@ -198,14 +481,50 @@ pub fn parse_commandline(argv: &[String]) -> Result<Config, String> {
         None => (),
     }
 
+    match matches.opt_str("conv-interval") {
+        Some(interval_str) => match from_str::<f64>(interval_str[]) {
+            Some(interval) if interval > 0.0 => config.convergence_interval = Some(interval),
+            _ => return Err(format!(
+                "Invalid convergence interval \"{}\", please see help (-h).", interval_str)),
+        },
+        None => (),
+    }
+
     if matches.opt_present("report") {
         config.full_report = true;
         // In report mode, the scene and algorithm options are ignored and managed by the reporter.
         return Ok(config);
     }
 
-    let mut scene = Scene::load_cornell_box(config.resolution, scene_config);
+    let mut scene = Scene::load_cornell_box(config.resolution, scene_config, &config.camera);
+    scene.set_light_selection(light_selection);
+    match spot_angles {
+        Some((inner, outer)) => scene.set_spot_light(inner, outer),
+        None => (),
+    }
+    match ies_profile {
+        Some(profile) => scene.set_point_light_profile(profile.to_image(256, 128)),
+        None => (),
+    }
     scene.build_scene_sphere();
+    match envmap {
+        Some(image) => {
+            let mut light = box EnvironmentLight::new(image, envmap_rotation);
+            light.scale = envmap_scale;
+            scene.set_background(light);
+        },
+        None => (),
+    }
+    match sky_turbidity {
+        Some(turbidity) => {
+            let mut light = box SunSkyLight::new(sun_direction, turbidity);
+            light.scale = sky_scale;
+            // The sky has its own sun
+            scene.remove_sun();
+            scene.set_background(light);
+        },
+        None => (),
+    }
     config.scene = Some(scene);
 
     config.output_name = match matches.opt_str("o") {
@@ -221,10 +540,43 @@ pub fn parse_commandline(argv: &[String]) -> Result<Config, String> {
 
     // Add a default extension if none's present
     if !config.output_name[].ends_with(".bmp") &&
-       !config.output_name[].ends_with(".hdr")
+       !config.output_name[].ends_with(".hdr") &&
+       !config.output_name[].ends_with(".pfm")
     {
         config.output_name.push_str(".bmp");
     }
 
+    match matches.opt_str("reference") {
+        Some(reference_name) => {
+            let reference = match Framebuffer::load_pfm(&Path::new(reference_name[])) {
+                Ok(reference) => reference,
+                Err(e) => return Err(format!(
+                    "Could not load reference image \"{}\": {}", reference_name, e)),
+            };
+
+            let res = reference.resolution();
+            if res.x as i32 != config.resolution.x || res.y as i32 != config.resolution.y {
+                return Err(format!(
+                    "Reference image \"{}\" has resolution {}, expected {}.",
+                    reference_name, res, config.resolution));
+            }
+
+            // Errors are only measured where something is rendered
+            let reference = match config.crop {
+                Some(crop) => reference.cropped(crop),
+                None => reference,
+            };
+            config.convergence = Some(ConvergenceLog::new(reference));
+            config.convergence_csv = match matches.opt_str("conv-csv") {
+                Some(csv_name) => csv_name,
+                None => {
+                    let base = config.output_name[].rsplitn(1, '.').last().unwrap();
+                    format!("{}.csv", base)
+                },
+            };
+        },
+        None => (),
+    }
+
     Ok(config)
 }
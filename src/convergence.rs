@@ -0,0 +1,80 @@
+use framebuffer::Framebuffer;
+use math::Vec3f;
+use std::io::{File, IoResult};
+use std::num::Float;
+use std::path::Path;
+
+pub struct ErrorMetrics {
+    pub rmse: f32,
+    /// Squared error relative to the reference, regularized to not blow up on black pixels.
+    pub rel_mse: f32,
+    pub mae: f32,
+}
+
+impl ErrorMetrics {
+    pub fn compute(image: &Framebuffer, reference: &Framebuffer) -> ErrorMetrics {
+        let mut sum_sqr = 0.0f64;
+        let mut sum_rel_sqr = 0.0f64;
+        let mut sum_abs = 0.0f64;
+
+        for (&x, &r) in image.pixels().iter().zip(reference.pixels().iter()) {
+            let diff: Vec3f = x - r;
+            for c in range(0, 3) {
+                let d = diff[c] as f64;
+                sum_sqr += d * d;
+                sum_rel_sqr += d * d / (r[c] as f64 * r[c] as f64 + 1e-2);
+                sum_abs += d.abs();
+            }
+        }
+
+        let n = (image.pixels().len() * 3) as f64;
+        ErrorMetrics {
+            rmse: (sum_sqr / n).sqrt() as f32,
+            rel_mse: (sum_rel_sqr / n) as f32,
+            mae: (sum_abs / n) as f32,
+        }
+    }
+}
+
+pub struct ConvergenceSample {
+    pub iterations: uint,
+    pub time: f64,
+    pub error: ErrorMetrics,
+}
+
+pub struct ConvergenceLog {
+    reference: Framebuffer,
+    pub samples: Vec<ConvergenceSample>,
+}
+
+impl ConvergenceLog {
+    pub fn new(reference: Framebuffer) -> ConvergenceLog {
+        ConvergenceLog {
+            reference: reference,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Records the error of the current estimate. `time` should only count time spent rendering,
+    /// so that the curves of different algorithms can be compared at equal time.
+    pub fn record(&mut self, iterations: uint, time: f64, image: &Framebuffer) {
+        let error = ErrorMetrics::compute(image, &self.reference);
+        self.samples.push(ConvergenceSample {
+            iterations: iterations,
+            time: time,
+            error: error,
+        });
+    }
+
+    pub fn save_csv(&self, filename: &Path) -> IoResult<()> {
+        let mut csv = try!(File::create(filename));
+        try!(csv.write_line("iterations,time,rmse,rel_mse,mae"));
+
+        for s in self.samples.iter() {
+            try!(csv.write_line(format!("{},{},{},{},{}",
+                s.iterations, s.time, s.error.rmse, s.error.rel_mse, s.error.mae)[]));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use math::{Vec2f, vec2};
+use std::cmp::min;
+use std::num::Float;
+
+/// Piecewise-constant 1D distribution over [0, 1), proportional to the given function values.
+pub struct Distribution1D {
+    func: Vec<f32>,
+    /// `func.len() + 1` entries, from 0 to 1.
+    cdf: Vec<f32>,
+    /// Integral of the function over [0, 1).
+    func_int: f32,
+}
+
+impl Distribution1D {
+    /// Builds the distribution from non-negative function values. If they're all zero, the
+    /// distribution is uniform.
+    pub fn new(func: Vec<f32>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in range(0, n) {
+            let prev = cdf[i];
+            cdf.push(prev + func[i] / n as f32);
+        }
+
+        let func_int = cdf[n];
+        for i in range(1, n + 1) {
+            cdf[mut][i] = if func_int > 0.0 { cdf[i] / func_int } else { i as f32 / n as f32 };
+        }
+
+        Distribution1D {
+            func: func,
+            cdf: cdf,
+            func_int: func_int,
+        }
+    }
+
+    pub fn count(&self) -> uint {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.func_int
+    }
+
+    /// Samples a position in [0, 1). Returns the position, its pdf and the index of the segment
+    /// it falls in.
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, uint) {
+        let index = self.find_segment(u);
+
+        let cdf_width = self.cdf[index + 1] - self.cdf[index];
+        let du = if cdf_width > 0.0 { (u - self.cdf[index]) / cdf_width } else { 0.5 };
+
+        let x = (index as f32 + du) / self.count() as f32;
+        (x, self.segment_pdf(index), index)
+    }
+
+    /// Samples a segment index with probability proportional to its function value. Returns
+    /// the index and its probability.
+    pub fn sample_discrete(&self, u: f32) -> (uint, f32) {
+        let index = self.find_segment(u);
+        (index, self.discrete_pdf(index))
+    }
+
+    /// Probability of `sample_discrete` returning `index`.
+    pub fn discrete_pdf(&self, index: uint) -> f32 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    /// Pdf of `sample_continuous` returning `x`.
+    pub fn pdf(&self, x: f32) -> f32 {
+        let index = min((x * self.count() as f32).max(0.0) as uint, self.count() - 1);
+        self.segment_pdf(index)
+    }
+
+    fn segment_pdf(&self, index: uint) -> f32 {
+        if self.func_int > 0.0 { self.func[index] / self.func_int } else { 1.0 }
+    }
+
+    /// Last segment whose cdf starts at or before `u`, skipping empty segments.
+    fn find_segment(&self, u: f32) -> uint {
+        // Binary search for the last cdf entry <= u
+        let mut lo = 0u;
+        let mut hi = self.count();
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+/// Piecewise-constant 2D distribution over [0, 1)^2, sampled by first picking a row from the
+/// marginal distribution, then a column within the row.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `func` holds `width * height` values in scanline order, with rows along v.
+    pub fn new(func: &[f32], width: uint, height: uint) -> Distribution2D {
+        let mut conditional = Vec::with_capacity(height);
+        for y in range(0, height) {
+            conditional.push(Distribution1D::new(func[y * width..(y + 1) * width].to_vec()));
+        }
+
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+
+        Distribution2D {
+            conditional: conditional,
+            marginal: marginal,
+        }
+    }
+
+    /// Integral of the function over [0, 1)^2.
+    pub fn integral(&self) -> f32 {
+        self.marginal.integral()
+    }
+
+    /// Returns the sampled position and its pdf with respect to area in [0, 1)^2.
+    pub fn sample_continuous(&self, rnd: Vec2f) -> (Vec2f, f32) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(rnd.y);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(rnd.x);
+        (vec2(u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, uv: Vec2f) -> f32 {
+        let height = self.conditional.len();
+        let row = min((uv.y * height as f32).max(0.0) as uint, height - 1);
+        self.marginal.pdf(uv.y) * self.conditional[row].pdf(uv.x)
+    }
+}
@@ -30,26 +30,26 @@ impl<'a> AbstractRenderer<'a> for EyeLight<'a> {
 
     fn run_iteration(&mut self, iteration: u32) {
         let scene = self.base.scene;
+        let window = self.base.trace_window;
 
-        let res_x = scene.camera.resolution.x as u32;
-        let res_y = scene.camera.resolution.y as u32;
-
-        for pix_id in range(0, res_x * res_y) {
-            let x = pix_id % res_x;
-            let y = pix_id / res_x;
+        for pix_id in range(0, window.num_pixels()) {
+            let (x, y) = window.pixel(pix_id);
 
             let sample = vec2(x as f32, y as f32) +
                 if iteration == 0 { vec2s(0.5) } else { self.rng.get_vec2f() };
 
-            let ray = scene.camera.generate_ray(sample);
+            let time = if iteration == 0 { 0.5 } else { self.rng.get_f32() };
+            let ray = scene.camera.generate_ray(sample, self.rng.get_vec2f(), time);
             let mut isect = Isect { dist: 1e36, ..Isect::new() };
 
             if scene.intersect(&ray, &mut isect) {
-                let dot_ln = isect.normal.dot(-ray.dir);
+                let dot_ln = isect.shading_normal.dot(-ray.dir);
 
-                self.base.framebuffer.add_color(sample,
+                self.base.film.add_color(sample,
                     if dot_ln > 0.0 { vec3s(dot_ln) }
                     else { vec3(-dot_ln, 0.0, 0.0) });
+            } else {
+                self.base.film.add_color(sample, vec3s(0.0));
             }
         }
 
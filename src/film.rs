@@ -0,0 +1,110 @@
+use framebuffer::{Framebuffer, CropWindow};
+use filter::Filter;
+use math::{Vec2f, Vec3f, vec2, vec3s};
+use std::num::Float;
+use std::cmp::{max, min};
+
+/// Accumulates samples into pixels through a reconstruction filter.
+///
+/// Camera samples are normalized by the filter weights they add to each pixel. Splats from
+/// light-driven algorithms already carry their measurement contribution and are only scaled by
+/// the number of iterations when resolving.
+#[deriving(Clone)]
+pub struct Film {
+    filter: Filter,
+    /// Scales splat weights so each splat adds its full color over all pixels.
+    splat_normalization: f32,
+    window: CropWindow,
+    color: Vec<Vec3f>,
+    weights: Vec<f32>,
+    splats: Vec<Vec3f>,
+}
+
+impl Film {
+    pub fn new(window: CropWindow, filter: Filter) -> Film {
+        let num_pixels = window.num_pixels();
+        Film {
+            filter: filter,
+            splat_normalization: 1.0 / filter.integral(),
+            window: window,
+            color: Vec::from_elem(num_pixels, vec3s(0.0)),
+            weights: Vec::from_elem(num_pixels, 0.0),
+            splats: Vec::from_elem(num_pixels, vec3s(0.0)),
+        }
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn window(&self) -> CropWindow {
+        self.window
+    }
+
+    /// Adds a camera sample at raster position `sample`. Every camera sample has to be added,
+    /// with black for rays that miss, as they all count towards the pixel's filter weight.
+    pub fn add_color(&mut self, sample: Vec2f, color: Vec3f) {
+        let width = self.window.size().x as uint;
+        let (x0, y0, x1, y1) = self.footprint(sample);
+        for y in range(y0, y1) {
+            for x in range(x0, x1) {
+                let weight = self.weight(sample, x, y);
+                let index = (x - self.window.min.x) as uint +
+                            (y - self.window.min.y) as uint * width;
+                self.color[mut][index] = self.color[index] + color * vec3s(weight);
+                self.weights[mut][index] += weight;
+            }
+        }
+    }
+
+    /// Adds a light-driven splat at raster position `sample`.
+    pub fn add_splat(&mut self, sample: Vec2f, color: Vec3f) {
+        let width = self.window.size().x as uint;
+        let (x0, y0, x1, y1) = self.footprint(sample);
+        for y in range(y0, y1) {
+            for x in range(x0, x1) {
+                let weight = self.weight(sample, x, y) * self.splat_normalization;
+                let index = (x - self.window.min.x) as uint +
+                            (y - self.window.min.y) as uint * width;
+                self.splats[mut][index] = self.splats[index] + color * vec3s(weight);
+            }
+        }
+    }
+
+    /// Range of pixels inside the window the filter around `sample` may cover, as
+    /// `(x0, y0, x1, y1)` with the upper bounds exclusive.
+    fn footprint(&self, sample: Vec2f) -> (i32, i32, i32, i32) {
+        let r = self.filter.radius();
+        let x0 = max((sample.x - 0.5 - r).ceil() as i32, self.window.min.x);
+        let y0 = max((sample.y - 0.5 - r).ceil() as i32, self.window.min.y);
+        let x1 = min((sample.x - 0.5 + r).floor() as i32 + 1, self.window.max.x);
+        let y1 = min((sample.y - 0.5 + r).floor() as i32 + 1, self.window.max.y);
+        (x0, y0, x1, y1)
+    }
+
+    fn weight(&self, sample: Vec2f, x: i32, y: i32) -> f32 {
+        let offset = vec2(x as f32 + 0.5, y as f32 + 0.5) - sample;
+        let r = self.filter.radius();
+
+        // Half-open on the negative side, so a sample on a pixel edge belongs to one box only
+        if offset.x <= -r || offset.y <= -r {
+            0.0
+        } else {
+            self.filter.evaluate(offset)
+        }
+    }
+
+    /// Resolves the image after `iterations` iterations, each adding one splat pass.
+    pub fn resolve(&self, iterations: u32) -> Framebuffer {
+        let splat_scale = if iterations > 0 { 1.0 / iterations as f32 } else { 0.0 };
+
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.setup_window(self.window);
+        for i in range(0, self.color.len()) {
+            let weight = self.weights[i];
+            let color = if weight != 0.0 { self.color[i] / vec3s(weight) } else { vec3s(0.0) };
+            framebuffer.set_pixel(i, color + self.splats[i] * vec3s(splat_scale));
+        }
+        framebuffer
+    }
+}
@@ -0,0 +1,103 @@
+use math::{Vec2f, PI};
+use std::num::{Float, FloatMath};
+
+/// Pixel reconstruction filters. All are separable, with the 1D filter applied along both axes.
+#[deriving(Copy)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    MitchellNetravali,
+    BlackmanHarris,
+}
+
+impl Filter {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Filter::Box => "box",
+            Filter::Tent => "tent",
+            Filter::Gaussian => "gaussian",
+            Filter::MitchellNetravali => "mitchell",
+            Filter::BlackmanHarris => "blackman-harris",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Filter> {
+        Some(match s {
+            "box"             => Filter::Box,
+            "tent"            => Filter::Tent,
+            "gaussian"        => Filter::Gaussian,
+            "mitchell"        => Filter::MitchellNetravali,
+            "blackman-harris" => Filter::BlackmanHarris,
+            _ => return None,
+        })
+    }
+
+    /// Half the width of the filter support, in pixels.
+    pub fn radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::MitchellNetravali => 2.0,
+            Filter::BlackmanHarris => 1.5,
+        }
+    }
+
+    /// Filter weight of a sample at `offset` from the pixel center. Can be negative.
+    pub fn evaluate(self, offset: Vec2f) -> f32 {
+        self.evaluate_1d(offset.x) * self.evaluate_1d(offset.y)
+    }
+
+    fn evaluate_1d(self, x: f32) -> f32 {
+        let r = self.radius();
+        let x = x.abs();
+        if x > r {
+            return 0.0;
+        }
+
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => r - x,
+            Filter::Gaussian => {
+                // Shifted down so it reaches zero at the radius
+                const ALPHA: f32 = 2.0;
+                ((-ALPHA * x * x).exp() - (-ALPHA * r * r).exp()).max(0.0)
+            },
+            Filter::MitchellNetravali => {
+                // B = C = 1/3, as recommended by Mitchell and Netravali
+                const B: f32 = 1.0 / 3.0;
+                const C: f32 = 1.0 / 3.0;
+                let x = 2.0 * x / r;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x * x * x +
+                     (-18.0 + 12.0 * B + 6.0 * C) * x * x +
+                     (6.0 - 2.0 * B)) / 6.0
+                } else {
+                    ((-B - 6.0 * C) * x * x * x +
+                     (6.0 * B + 30.0 * C) * x * x +
+                     (-12.0 * B - 48.0 * C) * x +
+                     (8.0 * B + 24.0 * C)) / 6.0
+                }
+            },
+            Filter::BlackmanHarris => {
+                let t = 2.0 * PI * (x + r) / (2.0 * r);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() -
+                    0.01168 * (3.0 * t).cos()
+            },
+        }
+    }
+
+    /// Integral of the filter over its support, for normalizing splats so they keep their energy.
+    pub fn integral(self) -> f32 {
+        const STEPS: uint = 256;
+
+        let r = self.radius();
+        let step = 2.0 * r / STEPS as f32;
+        let mut integral_1d = 0.0;
+        for i in range(0, STEPS) {
+            integral_1d += self.evaluate_1d(-r + (i as f32 + 0.5) * step) * step;
+        }
+        integral_1d * integral_1d
+    }
+}
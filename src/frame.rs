@@ -1,6 +1,7 @@
 use math::{Vec3f, vec3, vec3s};
 use std::num::Float;
 
+#[deriving(Copy, Clone)]
 pub struct Frame {
     pub x: Vec3f,
     pub y: Vec3f,
@@ -36,6 +37,26 @@ impl Frame {
         }
     }
 
+    /// Builds a frame around `z` with `y` as close as possible to `tangent`. Falls back to
+    /// `from_z` if the tangent is degenerate.
+    pub fn from_z_tangent(z: Vec3f, tangent: Vec3f) -> Frame {
+        let tmp_z = z.normalized();
+        let tmp_y = tangent - tmp_z * vec3s(tmp_z.dot(tangent));
+        let len_sqr = tmp_y.length_sqr();
+
+        if !(len_sqr > 1e-12) {
+            return Frame::from_z(tmp_z);
+        }
+
+        let tmp_y = tmp_y / vec3s(len_sqr.sqrt());
+
+        Frame {
+            x: tmp_y.cross(tmp_z),
+            y: tmp_y,
+            z: tmp_z,
+        }
+    }
+
     pub fn to_world(&self, a: Vec3f) -> Vec3f {
         self.x * vec3s(a.x) + self.y * vec3s(a.y) + self.z * vec3s(a.z)
     }
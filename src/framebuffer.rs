@@ -1,12 +1,58 @@
-use math::{Vec2f, Vec3f, vec2, vec3s};
+use math::{Vec2f, Vec2i, Vec3f, vec2, vec3, vec3s};
 use utils::luminance;
+use flate::inflate_bytes_zlib;
 use std::path::Path;
-use std::io::{File, IoResult};
+use std::io::{File, BufferedReader, BufReader, IoResult, IoError, InvalidInput};
 use std::num::{Float, FloatMath};
+use std::cmp::{max, min};
 
+/// Sub-rectangle of the image in pixels, from `min` inclusive to `max` exclusive.
+#[deriving(Copy)]
+pub struct CropWindow {
+    pub min: Vec2i,
+    pub max: Vec2i,
+}
+
+impl CropWindow {
+    pub fn new(min: Vec2i, max: Vec2i) -> CropWindow {
+        CropWindow { min: min, max: max }
+    }
+
+    pub fn full(resolution: Vec2f) -> CropWindow {
+        CropWindow::new(vec2(0, 0), vec2(resolution.x as i32, resolution.y as i32))
+    }
+
+    pub fn size(&self) -> Vec2i {
+        self.max - self.min
+    }
+
+    pub fn num_pixels(&self) -> uint {
+        let size = self.size();
+        (size.x * size.y) as uint
+    }
+
+    /// Window grown by `border` pixels on every side, clamped to an image of `resolution`.
+    pub fn grown(&self, border: i32, resolution: Vec2f) -> CropWindow {
+        let full = CropWindow::full(resolution);
+        CropWindow::new(vec2(max(self.min.x - border, full.min.x),
+                             max(self.min.y - border, full.min.y)),
+                        vec2(min(self.max.x + border, full.max.x),
+                             min(self.max.y + border, full.max.y)))
+    }
+
+    /// Raster coordinates of the pixel with the given index, in scanline order.
+    pub fn pixel(&self, index: uint) -> (u32, u32) {
+        let width = self.size().x as uint;
+        ((self.min.x as uint + index % width) as u32, (self.min.y as uint + index / width) as u32)
+    }
+}
+
+/// Image covering a window of the raster.
 #[deriving(Clone)]
 pub struct Framebuffer {
     color: Vec<Vec3f>,
+    /// Raster position of the first pixel.
+    origin: Vec2f,
     resolution: Vec2f,
     res_x: uint,
     res_y: uint,
@@ -16,36 +62,83 @@ impl Framebuffer {
     pub fn new() -> Framebuffer {
         Framebuffer {
             color: Vec::new(),
+            origin: vec2(0.0, 0.0),
             resolution: vec2(0.0, 0.0),
             res_x: 0,
             res_y: 0,
         }
     }
 
-    pub fn add_color(&mut self, sample: Vec2f, color: Vec3f) {
-        if sample.x < 0.0 || sample.x >= self.resolution.x {
-            return;
-        }
-        if sample.y < 0.0 || sample.y >= self.resolution.y {
-            return;
-        }
+    /// Sets a pixel by its index in scanline order.
+    pub fn set_pixel(&mut self, index: uint, color: Vec3f) {
+        self.color[mut][index] = color;
+    }
+
+    /// Size of the stored image, which is the crop window size for cropped framebuffers.
+    pub fn resolution(&self) -> Vec2f {
+        self.resolution
+    }
+
+    pub fn window(&self) -> CropWindow {
+        CropWindow::new(vec2(self.origin.x as i32, self.origin.y as i32),
+                        vec2((self.origin.x + self.resolution.x) as i32,
+                             (self.origin.y + self.resolution.y) as i32))
+    }
 
-        let (x, y) = (sample.x as uint, sample.y as uint);
-        //let src = self.color[x + y * self.res_x];
-        self.color[mut][x + y * self.res_x] = self.color[x + y * self.res_x] + color;
+    pub fn pixels(&self) -> &[Vec3f] {
+        self.color[]
     }
 
     pub fn setup(&mut self, resolution: Vec2f) {
-        let res_x = resolution.x as uint;
-        let res_y = resolution.y as uint;
+        self.setup_window(CropWindow::full(resolution));
+    }
+
+    /// Sets up the framebuffer to only store pixels inside `window`.
+    pub fn setup_window(&mut self, window: CropWindow) {
+        let size = window.size();
+        let res_x = size.x as uint;
+        let res_y = size.y as uint;
 
         self.color.clear();
         self.color.grow(res_x * res_y, vec3s(0.0));
-        self.resolution = resolution;
+        self.origin = vec2(window.min.x as f32, window.min.y as f32);
+        self.resolution = vec2(size.x as f32, size.y as f32);
         self.res_x = res_x;
         self.res_y = res_y;
     }
 
+    /// Copies the pixels inside `window`, which must lie inside this framebuffer.
+    pub fn cropped(&self, window: CropWindow) -> Framebuffer {
+        let mut res = Framebuffer::new();
+        res.setup_window(window);
+
+        let offset_x = (res.origin.x - self.origin.x) as uint;
+        let offset_y = (res.origin.y - self.origin.y) as uint;
+        for y in range(0, res.res_y) {
+            for x in range(0, res.res_x) {
+                res.color[mut][x + y * res.res_x] =
+                    self.color[(x + offset_x) + (y + offset_y) * self.res_x];
+            }
+        }
+        res
+    }
+
+    /// Places the image into a black full frame of the given resolution.
+    pub fn embedded(&self, resolution: Vec2f) -> Framebuffer {
+        let mut res = Framebuffer::new();
+        res.setup(resolution);
+
+        let offset_x = self.origin.x as uint;
+        let offset_y = self.origin.y as uint;
+        for y in range(0, self.res_y) {
+            for x in range(0, self.res_x) {
+                res.color[mut][(x + offset_x) + (y + offset_y) * res.res_x] =
+                    self.color[x + y * self.res_x];
+            }
+        }
+        res
+    }
+
     pub fn clear(&mut self) {
         for x in self.color.iter_mut() {
             *x = vec3s(0.0);
@@ -72,8 +165,384 @@ impl Framebuffer {
         unimplemented!() // TODO
     }
 
-    pub fn save_pfm(&self, _filename: &Path) {
-        unimplemented!() // TODO
+    pub fn save_pfm(&self, filename: &Path) -> IoResult<()> {
+        let mut pfm = try!(File::create(filename));
+        try!(pfm.write_str(format!("PF\n{} {}\n-1\n", self.res_x, self.res_y)[]));
+
+        // PFM stores scanlines bottom to top
+        for y in range(0, self.res_y) {
+            for x in range(0, self.res_x) {
+                let rgbf = self.color[x + (self.res_y - y - 1) * self.res_x];
+                try!(pfm.write_le_f32(rgbf.x));
+                try!(pfm.write_le_f32(rgbf.y));
+                try!(pfm.write_le_f32(rgbf.z));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load_pfm(filename: &Path) -> IoResult<Framebuffer> {
+        fn format_error(detail: &str) -> IoError {
+            IoError {
+                kind: InvalidInput,
+                desc: "invalid PFM file",
+                detail: Some(detail.to_string()),
+            }
+        }
+
+        let mut pfm = BufferedReader::new(try!(File::open(filename)));
+
+        let header = try!(pfm.read_line());
+        if header.trim() != "PF" {
+            return Err(format_error("only RGB (PF) images are supported"));
+        }
+
+        let dims = try!(pfm.read_line());
+        let dims: Vec<uint> = dims.words().filter_map(|s| from_str::<uint>(s)).collect();
+        if dims.len() != 2 {
+            return Err(format_error("malformed image dimensions"));
+        }
+        let (res_x, res_y) = (dims[0], dims[1]);
+        if res_x == 0 || res_y == 0 {
+            return Err(format_error("empty image"));
+        }
+
+        let scale = match from_str::<f32>(try!(pfm.read_line()).trim()) {
+            Some(scale) => scale,
+            None => return Err(format_error("malformed scale/endianness")),
+        };
+        let little_endian = scale < 0.0;
+
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.setup(vec2(res_x as f32, res_y as f32));
+
+        for y in range(0, res_y) {
+            for x in range(0, res_x) {
+                let mut rgb = vec3s(0.0);
+                for c in range(0, 3) {
+                    rgb[c] = if little_endian {
+                        try!(pfm.read_le_f32())
+                    } else {
+                        try!(pfm.read_be_f32())
+                    };
+                }
+                framebuffer.color[mut][x + (res_y - y - 1) * res_x] = rgb;
+            }
+        }
+
+        Ok(framebuffer)
+    }
+
+    /// Loads a Radiance RGBE (.hdr) image. Scanlines may be flat or run-length encoded with the
+    /// newer per-channel scheme; the old run-length scheme isn't supported.
+    pub fn load_hdr(filename: &Path) -> IoResult<Framebuffer> {
+        fn format_error(detail: &str) -> IoError {
+            IoError {
+                kind: InvalidInput,
+                desc: "invalid HDR file",
+                detail: Some(detail.to_string()),
+            }
+        }
+
+        fn rgbe_to_rgb(rgbe: [u8, ..4]) -> Vec3f {
+            if rgbe[3] == 0 {
+                return vec3s(0.0);
+            }
+            let f = 2.0f32.powi(rgbe[3] as i32 - 136);
+            vec3(rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f)
+        }
+
+        let mut hdr = BufferedReader::new(try!(File::open(filename)));
+
+        if !try!(hdr.read_line()).starts_with("#?") {
+            return Err(format_error("missing #? signature"));
+        }
+
+        // Header lines end with an empty line
+        loop {
+            let line = try!(hdr.read_line());
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+                return Err(format_error("only RGBE images are supported"));
+            }
+        }
+
+        let dims = try!(hdr.read_line());
+        let dims: Vec<&str> = dims.words().collect();
+        if dims.len() != 4 || dims[0] != "-Y" || dims[2] != "+X" {
+            return Err(format_error("only -Y H +X W orientation is supported"));
+        }
+        let (res_x, res_y) = match (from_str::<uint>(dims[3]), from_str::<uint>(dims[1])) {
+            (Some(w), Some(h)) => (w, h),
+            _ => return Err(format_error("malformed image dimensions")),
+        };
+        if res_x == 0 || res_y == 0 {
+            return Err(format_error("empty image"));
+        }
+
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.setup(vec2(res_x as f32, res_y as f32));
+
+        let mut scanline = Vec::from_elem(res_x * 4, 0u8);
+        for y in range(0, res_y) {
+            let mut start = [0u8, ..4];
+            for c in range(0, 4) {
+                start[c] = try!(hdr.read_u8());
+            }
+
+            let rle = res_x >= 8 && res_x < 32768 && start[0] == 2 && start[1] == 2 &&
+                      start[2] & 0x80 == 0;
+            if rle {
+                if (start[2] as uint) << 8 | start[3] as uint != res_x {
+                    return Err(format_error("wrong scanline width"));
+                }
+
+                // Each channel is stored separately as runs and literal spans
+                for c in range(0, 4) {
+                    let mut x = 0;
+                    while x < res_x {
+                        let count = try!(hdr.read_u8()) as uint;
+                        let (count, run) = if count > 128 { (count - 128, true) }
+                                           else { (count, false) };
+                        if count == 0 || x + count > res_x {
+                            return Err(format_error("bad scanline data"));
+                        }
+
+                        if run {
+                            let value = try!(hdr.read_u8());
+                            for i in range(x, x + count) {
+                                scanline[mut][i * 4 + c] = value;
+                            }
+                        } else {
+                            for i in range(x, x + count) {
+                                scanline[mut][i * 4 + c] = try!(hdr.read_u8());
+                            }
+                        }
+                        x += count;
+                    }
+                }
+            } else {
+                for c in range(0, 4) {
+                    scanline[mut][c] = start[c];
+                }
+                for i in range(4, res_x * 4) {
+                    scanline[mut][i] = try!(hdr.read_u8());
+                }
+            }
+
+            for x in range(0, res_x) {
+                let rgbe = [scanline[x * 4], scanline[x * 4 + 1], scanline[x * 4 + 2],
+                            scanline[x * 4 + 3]];
+                framebuffer.color[mut][x + y * res_x] = rgbe_to_rgb(rgbe);
+            }
+        }
+
+        Ok(framebuffer)
+    }
+
+    /// Loads the R, G and B channels of a single-part scanline OpenEXR image, either
+    /// uncompressed or ZIP compressed. Other compression schemes, tiled, deep and multi-part
+    /// images, and subsampled channels aren't supported.
+    pub fn load_exr(filename: &Path) -> IoResult<Framebuffer> {
+        fn format_error(detail: &str) -> IoError {
+            IoError {
+                kind: InvalidInput,
+                desc: "invalid OpenEXR file",
+                detail: Some(detail.to_string()),
+            }
+        }
+
+        fn read_name(reader: &mut BufReader) -> IoResult<String> {
+            let mut bytes = Vec::new();
+            loop {
+                match try!(reader.read_u8()) {
+                    0 => return Ok(String::from_utf8_lossy(bytes[]).into_owned()),
+                    b => bytes.push(b),
+                }
+            }
+        }
+
+        fn half_to_f32(h: u16) -> f32 {
+            let sign = if h & 0x8000 != 0 { -1.0f32 } else { 1.0 };
+            let exponent = ((h >> 10) & 0x1f) as i32;
+            let mantissa = (h & 0x3ff) as f32;
+            match exponent {
+                0 => sign * mantissa * 2.0f32.powi(-24),
+                31 if mantissa != 0.0 => Float::nan(),
+                31 => if sign > 0.0 { Float::infinity() } else { Float::neg_infinity() },
+                _ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent - 15),
+            }
+        }
+
+        /// Inflates a ZIP block, then undoes the byte predictor and the split of the bytes
+        /// into two halves that precede compression.
+        fn unzip(packed: &[u8], unpacked_size: uint) -> IoResult<Vec<u8>> {
+            let inflated = match inflate_bytes_zlib(packed) {
+                Some(inflated) => inflated,
+                None => return Err(format_error("bad ZIP data")),
+            };
+            let mut bytes = inflated.as_slice().to_vec();
+            if bytes.len() != unpacked_size {
+                return Err(format_error("wrong ZIP block size"));
+            }
+
+            for i in range(1, bytes.len()) {
+                bytes[mut][i] = (bytes[i - 1] as i32 + bytes[i] as i32 - 128) as u8;
+            }
+
+            let half = (bytes.len() + 1) / 2;
+            Ok(range(0, bytes.len()).map(|i| {
+                if i % 2 == 0 { bytes[i / 2] } else { bytes[half + i / 2] }
+            }).collect())
+        }
+
+        let data = try!(File::open(filename).read_to_end());
+        let mut header = BufReader::new(data[]);
+
+        if try!(header.read_le_u32()) != 20000630 {
+            return Err(format_error("missing magic number"));
+        }
+        let version = try!(header.read_le_u32());
+        if version & 0xff != 2 {
+            return Err(format_error("unsupported version"));
+        }
+        if version & 0x1a00 != 0 {
+            return Err(format_error("only single-part scanline images are supported"));
+        }
+
+        // Attributes are a name, a type name, a size and the value, until an empty name
+        let mut channels: Vec<(String, i32)> = Vec::new();
+        let mut compression = None;
+        let mut data_window = None;
+        loop {
+            let name = try!(read_name(&mut header));
+            if name.is_empty() {
+                break;
+            }
+            try!(read_name(&mut header));
+            let size = try!(header.read_le_i32());
+            if size < 0 {
+                return Err(format_error("negative attribute size"));
+            }
+            let bytes = try!(header.read_exact(size as uint));
+            let mut value = BufReader::new(bytes[]);
+
+            match name[] {
+                "channels" => loop {
+                    let channel = try!(read_name(&mut value));
+                    if channel.is_empty() {
+                        break;
+                    }
+                    let pixel_type = try!(value.read_le_i32());
+                    // Linearity flag and reserved bytes
+                    try!(value.read_exact(4));
+                    let sampling = (try!(value.read_le_i32()), try!(value.read_le_i32()));
+                    if sampling != (1, 1) {
+                        return Err(format_error("subsampled channels aren't supported"));
+                    }
+                    if pixel_type < 0 || pixel_type > 2 {
+                        return Err(format_error("unknown pixel type"));
+                    }
+                    channels.push((channel, pixel_type));
+                },
+                "compression" => compression = Some(try!(value.read_u8())),
+                "dataWindow" => data_window = Some((try!(value.read_le_i32()),
+                                                    try!(value.read_le_i32()),
+                                                    try!(value.read_le_i32()),
+                                                    try!(value.read_le_i32()))),
+                _ => (),
+            }
+        }
+
+        let (x_min, y_min, x_max, y_max) = match data_window {
+            Some(data_window) => data_window,
+            None => return Err(format_error("missing data window")),
+        };
+        if x_max < x_min || y_max < y_min {
+            return Err(format_error("empty image"));
+        }
+        let res_x = (x_max - x_min + 1) as uint;
+        let res_y = (y_max - y_min + 1) as uint;
+
+        // No compression, ZIP of single scanlines, or ZIP of 16 scanlines
+        let (zipped, lines_per_chunk) = match compression {
+            Some(0) => (false, 1),
+            Some(2) => (true, 1),
+            Some(3) => (true, 16),
+            Some(_) => return Err(format_error(
+                "only uncompressed and ZIP compressed images are supported")),
+            None => return Err(format_error("missing compression")),
+        };
+
+        // Scanlines store all values of one channel after another, in the order listed
+        let mut channel_offsets = Vec::with_capacity(channels.len());
+        let mut line_size = 0;
+        for &(_, pixel_type) in channels.iter() {
+            channel_offsets.push(line_size);
+            line_size += res_x * if pixel_type == 1 { 2 } else { 4 };
+        }
+        let mut rgb_channels = [0u, ..3];
+        for (c, name) in ["R", "G", "B"].iter().enumerate() {
+            match channels.iter().position(|&(ref channel, _)| channel[] == *name) {
+                Some(index) => rgb_channels[c] = index,
+                None => return Err(format_error("only RGB images are supported")),
+            }
+        }
+
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.setup(vec2(res_x as f32, res_y as f32));
+
+        // The offset table follows the header, with one entry per chunk
+        let num_chunks = (res_y + lines_per_chunk - 1) / lines_per_chunk;
+        for _ in range(0, num_chunks) {
+            let offset = try!(header.read_le_u64()) as uint;
+            if offset >= data.len() {
+                return Err(format_error("chunk offset out of range"));
+            }
+            let mut chunk = BufReader::new(data[offset..]);
+            let y = try!(chunk.read_le_i32());
+            let size = try!(chunk.read_le_i32());
+            if y < y_min || y > y_max || size < 0 {
+                return Err(format_error("bad chunk header"));
+            }
+            let packed = try!(chunk.read_exact(size as uint));
+
+            let first_line = (y - y_min) as uint;
+            let num_lines = min(lines_per_chunk, res_y - first_line);
+            let unpacked_size = num_lines * line_size;
+            // Blocks that don't shrink are stored uncompressed
+            let pixels = if zipped && packed.len() < unpacked_size {
+                try!(unzip(packed[], unpacked_size))
+            } else if packed.len() == unpacked_size {
+                packed
+            } else {
+                return Err(format_error("wrong chunk size"));
+            };
+
+            for line in range(0, num_lines) {
+                let row = first_line + line;
+                for c in range(0, 3) {
+                    let channel = rgb_channels[c];
+                    let (_, pixel_type) = channels[channel];
+                    let start = line * line_size + channel_offsets[channel];
+                    let mut values = BufReader::new(pixels[start..]);
+                    for x in range(0, res_x) {
+                        let value = match pixel_type {
+                            0 => try!(values.read_le_u32()) as f32,
+                            1 => half_to_f32(try!(values.read_le_u16())),
+                            _ => try!(values.read_le_f32()),
+                        };
+                        framebuffer.color[mut][x + row * res_x][c] = value;
+                    }
+                }
+            }
+        }
+
+        Ok(framebuffer)
     }
 
     pub fn save_bmp(&self, filename: &Path, gamma: f32) -> IoResult<()> {
@@ -1,15 +1,65 @@
 use ray::{Ray, Isect};
-use math::{Vec3f, vec3s};
+use frame::Frame;
+use math::{Vec3f, Vec2f, Aabb, vec2, vec3, vec3s, gamma, PI, INV_PI};
+use utils::{sample_uniform_triangle, sample_uniform_sphere_w, sample_concentric_disc};
 use std::num::{Float, FloatMath};
 
 pub trait AbstractGeometry {
+    /// Finds the closest intersection in `(ray.tmin, result.dist)`, updating `result` if found.
     fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool;
 
-    fn intersect_p(&self, ray: &Ray, result: &mut Isect) -> bool {
-        self.intersect(ray, result)
+    /// Tests for any intersection in `(ray.tmin, ray.tmax)`.
+    fn occluded(&self, ray: &Ray) -> bool;
+
+    fn bbox(&self) -> Aabb;
+
+    /// Lists the primitives whose material is emissive, as flagged by `emissive` per material
+    /// id, so lights can be created for them. Geometry that can't emit returns nothing.
+    fn emitters(&self, _emissive: &[bool]) -> Vec<Emitter> {
+        Vec::new()
     }
+}
+
+pub enum EmitterShape {
+    Triangle(Vec3f, Vec3f, Vec3f),
+    /// Center and radius.
+    Sphere(Vec3f, f32),
+    /// Center, normal and radius.
+    Disk(Vec3f, Vec3f, f32),
+    /// Corner and the two edges from it.
+    Quad(Vec3f, Vec3f, Vec3f),
+    /// Base, axis, radius and height.
+    Cylinder(Vec3f, Vec3f, f32, f32),
+}
+
+/// Emissive primitive, identified the same way as intersections are.
+pub struct Emitter {
+    pub mesh_id: int,
+    pub prim_id: int,
+    pub mat_id: int,
+    pub shape: EmitterShape,
+}
 
-    fn grow_bbox(&self, bbox_min: &mut Vec3f, bbox_max: &mut Vec3f);
+pub fn is_emissive(emissive: &[bool], mat_id: int) -> bool {
+    mat_id >= 0 && (mat_id as uint) < emissive.len() && emissive[mat_id as uint]
+}
+
+pub struct SurfaceSample {
+    pub position: Vec3f,
+    pub normal: Vec3f,
+    /// Pdf with respect to surface area.
+    pub pdf_a: f32,
+}
+
+/// Geometry with a finite surface that can be sampled, so it can also back area lights.
+pub trait AbstractShape : AbstractGeometry {
+    fn area(&self) -> f32;
+
+    /// Samples a point uniformly on the surface.
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample;
+
+    /// Normal at `position`, which has to lie on the surface.
+    fn normal(&self, position: Vec3f) -> Vec3f;
 }
 
 pub struct GeometryList {
@@ -22,31 +72,136 @@ impl GeometryList {
             geometry: Vec::new()
         }
     }
+
+    /// Folds the index of the child geometry into the primitive id, so primitives of different
+    /// children stay distinct when this list is nested inside another one, which overwrites
+    /// `mesh_id` with its own index.
+    fn fold_prim_id(&self, prim_id: int, index: uint) -> int {
+        prim_id * self.geometry.len() as int + index as int
+    }
 }
 
 impl AbstractGeometry for GeometryList {
     fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
         let mut any_intersection = false;
-        for geometry in self.geometry.iter() {
-            any_intersection |= geometry.intersect(ray, result);
+        for (i, geometry) in self.geometry.iter().enumerate() {
+            if geometry.intersect(ray, result) {
+                result.mesh_id = i as int;
+                result.prim_id = self.fold_prim_id(result.prim_id, i);
+                any_intersection = true;
+            }
         }
         any_intersection
     }
 
-    fn intersect_p(&self, ray: &Ray, result: &mut Isect) -> bool {
+    fn occluded(&self, ray: &Ray) -> bool {
         for geometry in self.geometry.iter() {
-            if geometry.intersect_p(ray, result) {
+            if geometry.occluded(ray) {
                 return true;
             }
         }
         false
     }
 
-    fn grow_bbox(&self, bbox_min: &mut Vec3f, bbox_max: &mut Vec3f) {
+    fn bbox(&self) -> Aabb {
+        let mut bbox = Aabb::empty();
         for geometry in self.geometry.iter() {
-            geometry.grow_bbox(bbox_min, bbox_max);
+            bbox = bbox.union(&geometry.bbox());
+        }
+        bbox
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        let mut emitters = Vec::new();
+        for (i, geometry) in self.geometry.iter().enumerate() {
+            for mut emitter in geometry.emitters(emissive).into_iter() {
+                emitter.mesh_id = i as int;
+                emitter.prim_id = self.fold_prim_id(emitter.prim_id, i);
+                emitters.push(emitter);
+            }
         }
+        emitters
+    }
+}
+
+/// Watertight ray-triangle intersection (Woop, Benthin and Wald, "Watertight Ray/Triangle
+/// Intersection", JCGT 2013). Rays hitting a shared edge or vertex always hit at least one of the
+/// adjacent triangles.
+///
+/// Returns the distance along the ray and the barycentric coordinates of `p1` and `p2` at the hit,
+/// if the ray passes through the triangle.
+pub fn intersect_triangle(p0: Vec3f, p1: Vec3f, p2: Vec3f, ray: &Ray) -> Option<(f32, Vec2f)> {
+    // Permute the axes so the ray direction's largest component is along z
+    let abs_dir = vec3(ray.dir.x.abs(), ray.dir.y.abs(), ray.dir.z.abs());
+    let kz = if abs_dir.x > abs_dir.y && abs_dir.x > abs_dir.z {
+        0
+    } else if abs_dir.y > abs_dir.z {
+        1
+    } else {
+        2
+    };
+    let (kx, ky) = if ray.dir[kz] < 0.0 {
+        // Swap to preserve the winding
+        ((kz + 2) % 3, (kz + 1) % 3)
+    } else {
+        ((kz + 1) % 3, (kz + 2) % 3)
+    };
+
+    // Shear so the ray points along +z
+    let sx = ray.dir[kx] / ray.dir[kz];
+    let sy = ray.dir[ky] / ray.dir[kz];
+    let sz = 1.0 / ray.dir[kz];
+
+    let a = p0 - ray.org;
+    let b = p1 - ray.org;
+    let c = p2 - ray.org;
+
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentrics, computed as 2D edge functions
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+
+    // Fall back to double precision on edges, so the sign is always computed consistently
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
     }
+
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t = u * az + v * bz + w * cz;
+
+    let inv_det = 1.0 / det;
+    Some((t * inv_det, vec2(v * inv_det, w * inv_det)))
+}
+
+fn abs_vec(v: Vec3f) -> Vec3f {
+    vec3(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+/// Error bound of a point computed by barycentric interpolation of the vertices.
+pub fn barycentric_error(p0: Vec3f, p1: Vec3f, p2: Vec3f, uv: Vec2f) -> Vec3f {
+    let b0 = 1.0 - uv.x - uv.y;
+    (abs_vec(p0 * vec3s(b0)) + abs_vec(p1 * vec3s(uv.x)) + abs_vec(p2 * vec3s(uv.y))) *
+        vec3s(gamma(7))
 }
 
 pub struct Triangle {
@@ -67,42 +222,46 @@ impl Triangle {
 
 impl AbstractGeometry for Triangle {
     fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
-        let ao = self.p[0] - ray.org;
-        let bo = self.p[1] - ray.org;
-        let co = self.p[2] - ray.org;
-
-        let v0 = co.cross(bo);
-        let v1 = bo.cross(ao);
-        let v2 = ao.cross(co);
-
-        let v0d = v0.dot(ray.dir);
-        let v1d = v1.dot(ray.dir);
-        let v2d = v2.dot(ray.dir);
-
-        if (v0d < 0.0 && v1d < 0.0 && v2d < 0.0) ||
-           (v0d >= 0.0 && v1d >= 0.0 && v2d >= 0.0) {
-            let distance = self.normal.dot(ao) / self.normal.dot(ray.dir);
-
-            if distance > ray.tmin && distance < result.dist {
-                result.normal = self.normal;
-                result.mat_id = self.mat_id;
+        match intersect_triangle(self.p[0], self.p[1], self.p[2], ray) {
+            Some((distance, uv)) if distance > ray.tmin && distance < result.dist => {
                 result.dist = distance;
-                return true;
-            }
+                result.mat_id = self.mat_id;
+                result.position = self.p[0] * vec3s(1.0 - uv.x - uv.y) +
+                                  self.p[1] * vec3s(uv.x) +
+                                  self.p[2] * vec3s(uv.y);
+                result.p_error = barycentric_error(self.p[0], self.p[1], self.p[2], uv);
+                result.uv = uv;
+                result.prim_id = 0;
+                result.geom_normal = self.normal;
+                result.shading_normal = self.normal;
+                result.dpdu = self.p[1] - self.p[0];
+                true
+            },
+            _ => false,
         }
+    }
 
-        false
+    fn occluded(&self, ray: &Ray) -> bool {
+        match intersect_triangle(self.p[0], self.p[1], self.p[2], ray) {
+            Some((distance, _)) => distance > ray.tmin && distance < ray.tmax,
+            None => false,
+        }
     }
 
-    fn grow_bbox(&self, bbox_min: &mut Vec3f, bbox_max: &mut Vec3f) {
-        for i in range(0, 3) {
-            for j in range(0, 3) {
-                let min = (*bbox_min)[j].min(self.p[i][j]);
-                bbox_min[j] = min;
-                let max = (*bbox_max)[j].max(self.p[i][j]);
-                bbox_max[j] = max;
-            }
+    fn bbox(&self) -> Aabb {
+        Aabb::from_point(self.p[0]).grow(self.p[1]).grow(self.p[2])
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        if !is_emissive(emissive, self.mat_id) {
+            return Vec::new();
         }
+        vec![Emitter {
+            mesh_id: 0,
+            prim_id: 0,
+            mat_id: self.mat_id,
+            shape: EmitterShape::Triangle(self.p[0], self.p[1], self.p[2]),
+        }]
     }
 }
 
@@ -120,10 +279,9 @@ impl Sphere {
             mat_id: mat_id,
         }
     }
-}
 
-impl AbstractGeometry for Sphere {
-    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+    /// Returns both distances along the ray to the sphere surface, in increasing order.
+    fn hit_distances(&self, ray: &Ray) -> Option<(f32, f32)> {
         let transformed_origin = ray.org - self.center;
 
         let a = ray.dir.dot(ray.dir);
@@ -133,7 +291,7 @@ impl AbstractGeometry for Sphere {
         let disc = (b*b - 4.0*a*c) as f64;
 
         if disc < 0.0 {
-            return false;
+            return None;
         }
 
         let disc_sqrt = disc.sqrt();
@@ -146,7 +304,16 @@ impl AbstractGeometry for Sphere {
         let t0 = (q / a as f64) as f32;
         let t1 = (c as f64 / q) as f32;
 
-        let (t0, t1) = if t0 > t1 { (t1, t0) } else { (t0, t1) };
+        Some(if t0 > t1 { (t1, t0) } else { (t0, t1) })
+    }
+}
+
+impl AbstractGeometry for Sphere {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        let (t0, t1) = match self.hit_distances(ray) {
+            Some(ts) => ts,
+            None => return false,
+        };
 
         let res_t = if t0 > ray.tmin && t0 < result.dist {
             t0
@@ -156,29 +323,510 @@ impl AbstractGeometry for Sphere {
             return false;
         };
 
+        let normal = (ray.org - self.center + vec3s(res_t) * ray.dir).normalized();
+
+        // Spherical coordinates with the poles on the z axis
+        let phi = normal.y.atan2(normal.x);
+        let theta = normal.z.max(-1.0).min(1.0).acos();
+
         result.dist = res_t as f32;
         result.mat_id = self.mat_id;
-        result.normal = (transformed_origin + vec3s(res_t) * ray.dir).normalized();
+        // Reprojected onto the surface, which is more accurate than following the ray
+        let offset = normal * vec3s(self.radius);
+        result.position = self.center + offset;
+        result.p_error = abs_vec(offset) * vec3s(gamma(5)) + abs_vec(result.position) * vec3s(gamma(1));
+        result.uv = vec2((phi + PI) * (0.5 * INV_PI), theta * INV_PI);
+        result.prim_id = 0;
+        result.geom_normal = normal;
+        result.shading_normal = normal;
+        result.dpdu = vec3(-normal.y, normal.x, 0.0);
         return true;
     }
 
-    fn grow_bbox(&self, bbox_min: &mut Vec3f, bbox_max: &mut Vec3f) {
-        for i in range(0u, 8) {
-            let mut p = self.center;
-            let mut half = vec3s(self.radius);
+    fn occluded(&self, ray: &Ray) -> bool {
+        match self.hit_distances(ray) {
+            Some((t0, t1)) => (t0 > ray.tmin && t0 < ray.tmax) || (t1 > ray.tmin && t1 < ray.tmax),
+            None => false,
+        }
+    }
+
+    fn bbox(&self) -> Aabb {
+        Aabb::new(self.center - vec3s(self.radius), self.center + vec3s(self.radius))
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        if !is_emissive(emissive, self.mat_id) {
+            return Vec::new();
+        }
+        vec![Emitter {
+            mesh_id: 0,
+            prim_id: 0,
+            mat_id: self.mat_id,
+            shape: EmitterShape::Sphere(self.center, self.radius),
+        }]
+    }
+}
+
+impl AbstractShape for Triangle {
+    fn area(&self) -> f32 {
+        0.5 * (self.p[1] - self.p[0]).cross(self.p[2] - self.p[0]).length()
+    }
+
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample {
+        let bary = sample_uniform_triangle(rnd);
+
+        SurfaceSample {
+            position: self.p[0] * vec3s(bary.x) +
+                      self.p[1] * vec3s(bary.y) +
+                      self.p[2] * vec3s(1.0 - bary.x - bary.y),
+            normal: self.normal,
+            pdf_a: 1.0 / self.area(),
+        }
+    }
+
+    fn normal(&self, _position: Vec3f) -> Vec3f {
+        self.normal
+    }
+}
+
+impl AbstractShape for Sphere {
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample {
+        let (normal, _) = sample_uniform_sphere_w(rnd);
+
+        SurfaceSample {
+            position: self.center + normal * vec3s(self.radius),
+            normal: normal,
+            pdf_a: 1.0 / self.area(),
+        }
+    }
+
+    fn normal(&self, position: Vec3f) -> Vec3f {
+        (position - self.center) / vec3s(self.radius)
+    }
+}
+
+/// One-sided disk. The normal determines the front side, but it intersects from both sides.
+pub struct Disk {
+    pub center: Vec3f,
+    pub radius: f32,
+    pub mat_id: int,
+    frame: Frame,
+}
+
+impl Disk {
+    pub fn new(center: Vec3f, normal: Vec3f, radius: f32, mat_id: int) -> Disk {
+        Disk {
+            center: center,
+            radius: radius,
+            mat_id: mat_id,
+            frame: Frame::from_z(normal),
+        }
+    }
+
+    /// Returns the distance along the ray and the hit in the disk's local planar coordinates.
+    fn hit_distance(&self, ray: &Ray) -> Option<(f32, Vec2f)> {
+        let normal = self.frame.normal();
+        let denom = normal.dot(ray.dir);
+        if denom == 0.0 {
+            return None;
+        }
+
+        let t = normal.dot(self.center - ray.org) / denom;
+        let local = self.frame.to_local(ray.org + ray.dir * vec3s(t) - self.center);
+
+        if local.x * local.x + local.y * local.y > self.radius * self.radius {
+            return None;
+        }
+
+        Some((t, local.get_xy()))
+    }
+}
+
+impl AbstractGeometry for Disk {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        let (t, local) = match self.hit_distance(ray) {
+            Some((t, local)) if t > ray.tmin && t < result.dist => (t, local),
+            _ => return false,
+        };
+
+        let r = local.dot(local).sqrt();
+        let phi = local.y.atan2(local.x);
+
+        // Rebuilt from the planar coordinates, so it lies exactly on the plane
+        let offset = self.frame.x * vec3s(local.x) + self.frame.y * vec3s(local.y);
+
+        result.dist = t;
+        result.mat_id = self.mat_id;
+        result.position = self.center + offset;
+        result.p_error = (abs_vec(self.center) + abs_vec(offset)) * vec3s(gamma(5));
+        result.uv = vec2(r / self.radius, (phi + PI) * (0.5 * INV_PI));
+        result.prim_id = 0;
+        result.geom_normal = self.frame.normal();
+        result.shading_normal = self.frame.normal();
+        result.dpdu = offset;
+        true
+    }
 
-            for j in range(0, 3) {
-                if (i & (1 << j)) != 0 {
-                    half[j] = -half[j];
+    fn occluded(&self, ray: &Ray) -> bool {
+        match self.hit_distance(ray) {
+            Some((t, _)) => t > ray.tmin && t < ray.tmax,
+            None => false,
+        }
+    }
+
+    fn bbox(&self) -> Aabb {
+        // Extent of a circle along each axis
+        let n = self.frame.normal();
+        let extent = vec3((1.0 - n.x * n.x).max(0.0).sqrt(),
+                          (1.0 - n.y * n.y).max(0.0).sqrt(),
+                          (1.0 - n.z * n.z).max(0.0).sqrt()) * vec3s(self.radius);
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        if !is_emissive(emissive, self.mat_id) {
+            return Vec::new();
+        }
+        vec![Emitter {
+            mesh_id: 0,
+            prim_id: 0,
+            mat_id: self.mat_id,
+            shape: EmitterShape::Disk(self.center, self.frame.normal(), self.radius),
+        }]
+    }
+}
+
+impl AbstractShape for Disk {
+    fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample {
+        let local = sample_concentric_disc(rnd);
+
+        SurfaceSample {
+            position: self.center + self.frame.to_world(vec3(local.x, local.y, 0.0)) *
+                                    vec3s(self.radius),
+            normal: self.frame.normal(),
+            pdf_a: 1.0 / self.area(),
+        }
+    }
+
+    fn normal(&self, _position: Vec3f) -> Vec3f {
+        self.frame.normal()
+    }
+}
+
+/// Parallelogram spanned by `e1` and `e2` from `p0`. The normal is `e1 x e2`.
+pub struct Quad {
+    p0: Vec3f,
+    e1: Vec3f,
+    e2: Vec3f,
+    normal: Vec3f,
+    pub mat_id: int,
+}
+
+impl Quad {
+    pub fn new(p0: Vec3f, e1: Vec3f, e2: Vec3f, mat_id: int) -> Quad {
+        Quad {
+            p0: p0,
+            e1: e1,
+            e2: e2,
+            normal: e1.cross(e2).normalized(),
+            mat_id: mat_id,
+        }
+    }
+
+    /// Returns the distance along the ray and the coordinates of the hit along `e1` and `e2`.
+    fn hit_distance(&self, ray: &Ray) -> Option<(f32, Vec2f)> {
+        let n = self.e1.cross(self.e2);
+        let denom = n.dot(ray.dir);
+        if denom == 0.0 {
+            return None;
+        }
+
+        let t = n.dot(self.p0 - ray.org) / denom;
+        let d = ray.org + ray.dir * vec3s(t) - self.p0;
+
+        let inv_n_len_sqr = 1.0 / n.length_sqr();
+        let u = d.cross(self.e2).dot(n) * inv_n_len_sqr;
+        let v = self.e1.cross(d).dot(n) * inv_n_len_sqr;
+
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            return None;
+        }
+
+        Some((t, vec2(u, v)))
+    }
+}
+
+impl AbstractGeometry for Quad {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        let (t, uv) = match self.hit_distance(ray) {
+            Some((t, uv)) if t > ray.tmin && t < result.dist => (t, uv),
+            _ => return false,
+        };
+
+        let offset_u = self.e1 * vec3s(uv.x);
+        let offset_v = self.e2 * vec3s(uv.y);
+
+        result.dist = t;
+        result.mat_id = self.mat_id;
+        result.position = self.p0 + offset_u + offset_v;
+        result.p_error = (abs_vec(self.p0) + abs_vec(offset_u) + abs_vec(offset_v)) *
+                         vec3s(gamma(7));
+        result.uv = uv;
+        result.prim_id = 0;
+        result.geom_normal = self.normal;
+        result.shading_normal = self.normal;
+        result.dpdu = self.e1;
+        true
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        match self.hit_distance(ray) {
+            Some((t, _)) => t > ray.tmin && t < ray.tmax,
+            None => false,
+        }
+    }
+
+    fn bbox(&self) -> Aabb {
+        Aabb::from_point(self.p0)
+            .grow(self.p0 + self.e1)
+            .grow(self.p0 + self.e2)
+            .grow(self.p0 + self.e1 + self.e2)
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        if !is_emissive(emissive, self.mat_id) {
+            return Vec::new();
+        }
+        vec![Emitter {
+            mesh_id: 0,
+            prim_id: 0,
+            mat_id: self.mat_id,
+            shape: EmitterShape::Quad(self.p0, self.e1, self.e2),
+        }]
+    }
+}
+
+impl AbstractShape for Quad {
+    fn area(&self) -> f32 {
+        self.e1.cross(self.e2).length()
+    }
+
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample {
+        SurfaceSample {
+            position: self.p0 + self.e1 * vec3s(rnd.x) + self.e2 * vec3s(rnd.y),
+            normal: self.normal,
+            pdf_a: 1.0 / self.area(),
+        }
+    }
+
+    fn normal(&self, _position: Vec3f) -> Vec3f {
+        self.normal
+    }
+}
+
+/// Open cylinder of the given height, starting at `base` and extending along `axis`. Normals
+/// point outwards.
+pub struct Cylinder {
+    pub base: Vec3f,
+    pub radius: f32,
+    pub height: f32,
+    pub mat_id: int,
+    frame: Frame,
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3f, axis: Vec3f, radius: f32, height: f32, mat_id: int) -> Cylinder {
+        Cylinder {
+            base: base,
+            radius: radius,
+            height: height,
+            mat_id: mat_id,
+            frame: Frame::from_z(axis),
+        }
+    }
+
+    /// Returns the nearest hit in `(tmin, tmax)` and its position in local coordinates.
+    fn hit_distance(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<(f32, Vec3f)> {
+        let o = self.frame.to_local(ray.org - self.base);
+        let d = self.frame.to_local(ray.dir);
+
+        let a = (d.x * d.x + d.y * d.y) as f64;
+        let b = 2.0 * (d.x * o.x + d.y * o.y) as f64;
+        let c = (o.x * o.x + o.y * o.y - self.radius * self.radius) as f64;
+
+        if a == 0.0 {
+            // Parallel to the axis
+            return None;
+        }
+
+        let disc = b * b - 4.0 * a * c;
+        if disc < 0.0 {
+            return None;
+        }
+
+        let disc_sqrt = disc.sqrt();
+        let q = if b < 0.0 { (-b - disc_sqrt) / 2.0 } else { (-b + disc_sqrt) / 2.0 };
+        let t0 = (q / a) as f32;
+        let t1 = (c / q) as f32;
+        let (t0, t1) = if t0 > t1 { (t1, t0) } else { (t0, t1) };
+
+        for &t in [t0, t1].iter() {
+            if t > tmin && t < tmax {
+                let local = o + d * vec3s(t);
+                if local.z >= 0.0 && local.z <= self.height {
+                    return Some((t, local));
                 }
             }
+        }
+
+        None
+    }
+}
+
+impl AbstractGeometry for Cylinder {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        let (t, local) = match self.hit_distance(ray, ray.tmin, result.dist) {
+            Some(hit) => hit,
+            None => return false,
+        };
+
+        // Reproject onto the surface
+        let radial_len = (local.x * local.x + local.y * local.y).sqrt();
+        let radial = vec3(local.x, local.y, 0.0) / vec3s(radial_len);
+        let normal = self.frame.to_world(radial);
+        let offset = normal * vec3s(self.radius) + self.frame.normal() * vec3s(local.z);
+        let phi = radial.y.atan2(radial.x);
+
+        result.dist = t;
+        result.mat_id = self.mat_id;
+        result.position = self.base + offset;
+        result.p_error = abs_vec(offset) * vec3s(gamma(7)) + abs_vec(self.base) * vec3s(gamma(1));
+        result.uv = vec2((phi + PI) * (0.5 * INV_PI), local.z / self.height);
+        result.prim_id = 0;
+        result.geom_normal = normal;
+        result.shading_normal = normal;
+        result.dpdu = self.frame.to_world(vec3(-radial.y, radial.x, 0.0));
+        true
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.hit_distance(ray, ray.tmin, ray.tmax).is_some()
+    }
 
-            p = p + half;
+    fn bbox(&self) -> Aabb {
+        let mut bbox = Aabb::empty();
+        for i in range(0u, 8) {
+            let corner = vec3(if (i & 1) != 0 { self.radius } else { -self.radius },
+                              if (i & 2) != 0 { self.radius } else { -self.radius },
+                              if (i & 4) != 0 { self.height } else { 0.0 });
+            bbox = bbox.grow(self.base + self.frame.to_world(corner));
+        }
+        bbox
+    }
 
-            for j in range(0, 3) {
-                bbox_min[j] = (*bbox_min)[j].min(p[j]);
-                bbox_max[j] = (*bbox_max)[j].max(p[j]);
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        if !is_emissive(emissive, self.mat_id) {
+            return Vec::new();
+        }
+        vec![Emitter {
+            mesh_id: 0,
+            prim_id: 0,
+            mat_id: self.mat_id,
+            shape: EmitterShape::Cylinder(self.base, self.frame.normal(), self.radius,
+                                          self.height),
+        }]
+    }
+}
+
+impl AbstractShape for Cylinder {
+    fn area(&self) -> f32 {
+        2.0 * PI * self.radius * self.height
+    }
+
+    fn sample(&self, rnd: Vec2f) -> SurfaceSample {
+        let phi = 2.0 * PI * rnd.y;
+        let radial = vec3(phi.cos(), phi.sin(), 0.0);
+        let normal = self.frame.to_world(radial);
+
+        SurfaceSample {
+            position: self.base + normal * vec3s(self.radius) +
+                      self.frame.normal() * vec3s(rnd.x * self.height),
+            normal: normal,
+            pdf_a: 1.0 / self.area(),
+        }
+    }
+
+    fn normal(&self, position: Vec3f) -> Vec3f {
+        let local = self.frame.to_local(position - self.base);
+        self.frame.to_world(vec3(local.x, local.y, 0.0).normalized())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use camera::CameraSettings;
+    use math::{Vec3f, vec2, vec3, vec3s};
+    use ray::{Ray, Isect};
+    use scene::{Scene, LIGHT_POINT, cornell_box_corners};
+
+    const SAMPLES_PER_EDGE: uint = 2000;
+
+    /// Shoots rays from inside the box at evenly spaced points along the edge from `a` to `b`,
+    /// returning how many escape without a hit.
+    fn count_escaping(scene: &Scene, a: Vec3f, b: Vec3f) -> uint {
+        let origins = [vec3(0.0, 0.0, 0.0), vec3(0.3, -0.4, 0.2), vec3(-0.5, 0.6, -0.7)];
+        let mut escaping = 0;
+        for org in origins.iter() {
+            for i in range(1, SAMPLES_PER_EDGE) {
+                let t = i as f32 / SAMPLES_PER_EDGE as f32;
+                let target = a + (b - a) * vec3s(t);
+                let ray = Ray {
+                    org: *org,
+                    dir: (target - *org).normalized(),
+                    tmin: 0.0,
+                    tmax: 1e36,
+                    time: 0.5,
+                };
+                if !scene.intersect(&ray, &mut Isect::new()) {
+                    escaping += 1;
+                }
             }
         }
+        escaping
+    }
+
+    /// The point light variant has no light box or spheres, leaving only the walls.
+    fn empty_cornell_box() -> Scene {
+        Scene::load_cornell_box(vec2(16, 16), LIGHT_POINT, &CameraSettings::new())
+    }
+
+    #[test]
+    fn cornell_box_diagonals_are_watertight() {
+        let scene = empty_cornell_box();
+        let cb = cornell_box_corners();
+        // Shared edges of the two triangles of the floor, back, ceiling, left and right walls
+        let diagonals = [(0, 5), (0, 2), (2, 7), (4, 3), (1, 6)];
+        for &(i, j) in diagonals.iter() {
+            assert_eq!(count_escaping(&scene, cb[i], cb[j]), 0);
+        }
+    }
+
+    #[test]
+    fn cornell_box_corners_are_watertight() {
+        let scene = empty_cornell_box();
+        let cb = cornell_box_corners();
+        // Edges where two walls meet, leaving out the open front
+        let edges = [(0, 1), (1, 2), (2, 3), (3, 0), (0, 4), (1, 5), (2, 6), (3, 7)];
+        for &(i, j) in edges.iter() {
+            assert_eq!(count_escaping(&scene, cb[i], cb[j]), 0);
+        }
     }
 }
@@ -0,0 +1,163 @@
+use math::{vec2, vec3s};
+use framebuffer::Framebuffer;
+use std::path::Path;
+use std::io::{File, IoResult, IoError, InvalidInput};
+
+/// Luminous intensity distribution from an IES LM-63 photometric file. Only type C photometry
+/// without tilt data is supported.
+pub struct IesProfile {
+    /// Polar angles from the nadir, in degrees.
+    vertical_angles: Vec<f32>,
+    /// Azimuthal angles, in degrees.
+    horizontal_angles: Vec<f32>,
+    /// Candela values, one row of vertical angles per horizontal angle.
+    candela: Vec<f32>,
+    pub max_candela: f32,
+}
+
+impl IesProfile {
+    pub fn load(filename: &Path) -> IoResult<IesProfile> {
+        fn format_error(detail: &str) -> IoError {
+            IoError {
+                kind: InvalidInput,
+                desc: "invalid IES file",
+                detail: Some(detail.to_string()),
+            }
+        }
+
+        let contents = try!(try!(File::open(filename)).read_to_string());
+
+        // Keywords come first, the photometric data follows the TILT line
+        let mut lines = contents[].lines();
+        loop {
+            match lines.next() {
+                Some(line) => {
+                    let line = line.trim();
+                    if line.starts_with("TILT=") {
+                        if line != "TILT=NONE" {
+                            return Err(format_error("tilt data isn't supported"));
+                        }
+                        break;
+                    }
+                },
+                None => return Err(format_error("missing TILT line")),
+            }
+        }
+
+        let mut values = Vec::new();
+        for line in lines {
+            for word in line.split(|c: char| c.is_whitespace() || c == ',') {
+                if word.is_empty() {
+                    continue;
+                }
+                match from_str::<f32>(word) {
+                    Some(value) => values.push(value),
+                    None => return Err(format_error("malformed number")),
+                }
+            }
+        }
+
+        if values.len() < 13 {
+            return Err(format_error("truncated header"));
+        }
+        let candela_multiplier = values[2];
+        let num_vertical = values[3] as uint;
+        let num_horizontal = values[4] as uint;
+        if values[5] as uint != 1 {
+            return Err(format_error("only type C photometry is supported"));
+        }
+        let ballast_factor = values[10];
+
+        let data = values[13..];
+        if num_vertical == 0 || num_horizontal == 0 ||
+           data.len() < num_vertical + num_horizontal + num_vertical * num_horizontal {
+            return Err(format_error("truncated photometric data"));
+        }
+
+        let vertical_angles = data[..num_vertical].to_vec();
+        let horizontal_angles = data[num_vertical..num_vertical + num_horizontal].to_vec();
+        let scale = candela_multiplier * ballast_factor;
+        let candela: Vec<f32> = data[num_vertical + num_horizontal..]
+            .iter().take(num_vertical * num_horizontal).map(|&c| c * scale).collect();
+        let max_candela = candela.iter().fold(0.0, |a, &b| if b > a { b } else { a });
+
+        Ok(IesProfile {
+            vertical_angles: vertical_angles,
+            horizontal_angles: horizontal_angles,
+            candela: candela,
+            max_candela: max_candela,
+        })
+    }
+
+    /// Returns the intensity in candela at polar angle `theta` from the nadir and azimuth `phi`,
+    /// both in degrees.
+    pub fn evaluate(&self, theta: f32, phi: f32) -> f32 {
+        // Fold the azimuth into the range covered by the file, according to its symmetry
+        let last_horizontal = self.horizontal_angles[self.horizontal_angles.len() - 1];
+        let mut phi = phi % 360.0;
+        if phi < 0.0 {
+            phi += 360.0;
+        }
+        if last_horizontal <= 180.0 && phi > 180.0 {
+            phi = 360.0 - phi;
+        }
+        if last_horizontal <= 90.0 && phi > 90.0 {
+            phi = 180.0 - phi;
+        }
+
+        let (v0, v1, tv) = match find_segment(self.vertical_angles[], theta) {
+            Some(segment) => segment,
+            None => return 0.0,
+        };
+        let (h0, h1, th) = match find_segment(self.horizontal_angles[], phi) {
+            Some(segment) => segment,
+            None => (0, 0, 0.0),
+        };
+
+        let c0 = self.candela_at(h0, v0) * (1.0 - tv) + self.candela_at(h0, v1) * tv;
+        let c1 = self.candela_at(h1, v0) * (1.0 - tv) + self.candela_at(h1, v1) * tv;
+        c0 * (1.0 - th) + c1 * th
+    }
+
+    fn candela_at(&self, horizontal: uint, vertical: uint) -> f32 {
+        self.candela[vertical + horizontal * self.vertical_angles.len()]
+    }
+
+    /// Resamples the profile into an equirectangular image with the nadir as its top row,
+    /// normalized so the brightest direction is 1.
+    pub fn to_image(&self, res_x: uint, res_y: uint) -> Framebuffer {
+        let mut image = Framebuffer::new();
+        image.setup(vec2(res_x as f32, res_y as f32));
+
+        let inv_max = if self.max_candela > 0.0 { 1.0 / self.max_candela } else { 0.0 };
+        for y in range(0, res_y) {
+            let theta = (y as f32 + 0.5) / res_y as f32 * 180.0;
+            for x in range(0, res_x) {
+                let phi = (x as f32 + 0.5) / res_x as f32 * 360.0;
+                image.set_pixel(x + y * res_x, vec3s(self.evaluate(theta, phi) * inv_max));
+            }
+        }
+        image
+    }
+}
+
+/// Finds the two angles around `angle` and the interpolation weight between them. A single
+/// angle covers everything.
+fn find_segment(angles: &[f32], angle: f32) -> Option<(uint, uint, f32)> {
+    let last = angles.len() - 1;
+    if last == 0 {
+        return Some((0, 0, 0.0));
+    }
+    if angle < angles[0] || angle > angles[last] {
+        return None;
+    }
+
+    let mut i = 0;
+    while i + 1 < last && angles[i + 1] < angle {
+        i += 1;
+    }
+
+    let width = angles[i + 1] - angles[i];
+    let t = if width > 0.0 { (angle - angles[i]) / width } else { 0.0 };
+    Some((i, i + 1, t))
+}
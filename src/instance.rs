@@ -0,0 +1,175 @@
+use geometry::{AbstractGeometry, Emitter, EmitterShape};
+use math::{Mat4f, Vec3f, Aabb, vec3, vec3s, gamma};
+use ray::{Ray, Isect};
+use std::num::Float;
+use std::sync::Arc;
+
+pub type SharedGeometry = Arc<Box<AbstractGeometry + Sync + Send + 'static>>;
+
+/// Places shared geometry in the scene with an affine transform, so repeated objects don't
+/// duplicate their data.
+pub struct Instance {
+    object: SharedGeometry,
+    /// Transform at shutter open.
+    to_world: Mat4f,
+    to_object: Mat4f,
+    /// Transform at shutter close, if the instance moves.
+    end_to_world: Option<Mat4f>,
+}
+
+impl Instance {
+    pub fn new(object: SharedGeometry, to_world: Mat4f) -> Instance {
+        Instance {
+            object: object,
+            to_world: to_world,
+            to_object: to_world.inverted(),
+            end_to_world: None,
+        }
+    }
+
+    /// Instance moving from `start_to_world` to `end_to_world` over the shutter interval, with
+    /// the transform interpolated linearly in between.
+    pub fn moving(object: SharedGeometry, start_to_world: Mat4f,
+                  end_to_world: Mat4f) -> Instance {
+        Instance {
+            end_to_world: Some(end_to_world),
+            ..Instance::new(object, start_to_world)
+        }
+    }
+
+    /// Returns the object to world transform at `time` and its inverse.
+    fn transforms_at(&self, time: f32) -> (Mat4f, Mat4f) {
+        match self.end_to_world {
+            Some(ref end_to_world) => {
+                let to_world = self.to_world.lerp(end_to_world, time);
+                (to_world, to_world.inverted())
+            },
+            None => (self.to_world, self.to_object),
+        }
+    }
+}
+
+/// The direction is not normalized, so distances along the ray are the same in both spaces.
+fn object_ray(to_object: &Mat4f, ray: &Ray) -> Ray {
+    Ray {
+        org: to_object.transform_point(&ray.org),
+        dir: to_object.transform_vector(&ray.dir),
+        tmin: ray.tmin,
+        tmax: ray.tmax,
+        time: ray.time,
+    }
+}
+
+/// Transforms a point with error bound `p_error`, returning the error bound of the result.
+fn transform_point_error(m: &Mat4f, p: Vec3f, p_error: Vec3f) -> Vec3f {
+    let mut res = vec3s(0.0);
+    for r in range(0, 3) {
+        let mut rounding = m[(r, 3)].abs();
+        let mut propagated = 0.0;
+        for c in range(0, 3) {
+            rounding += (m[(r, c)] * p[c]).abs();
+            propagated += m[(r, c)].abs() * p_error[c];
+        }
+        res[r] = gamma(3) * rounding + (gamma(3) + 1.0) * propagated;
+    }
+    res
+}
+
+/// Returns the scale factor of `m` if it scales all directions equally, so curved shapes keep
+/// their shape, and whether it mirrors.
+fn uniform_scale(m: &Mat4f) -> (Option<f32>, bool) {
+    let x = m.transform_vector(&vec3(1.0, 0.0, 0.0));
+    let y = m.transform_vector(&vec3(0.0, 1.0, 0.0));
+    let z = m.transform_vector(&vec3(0.0, 0.0, 1.0));
+    let mirrored = x.cross(y).dot(z) < 0.0;
+
+    let scale = x.length();
+    let tolerance = scale * 1e-4;
+    let uniform = (y.length() - scale).abs() <= tolerance &&
+                  (z.length() - scale).abs() <= tolerance &&
+                  x.dot(y).abs() <= tolerance * scale &&
+                  x.dot(z).abs() <= tolerance * scale &&
+                  y.dot(z).abs() <= tolerance * scale;
+    (if uniform { Some(scale) } else { None }, mirrored)
+}
+
+impl AbstractGeometry for Instance {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        let (to_world, to_object) = self.transforms_at(ray.time);
+        if !self.object.intersect(&object_ray(&to_object, ray), result) {
+            return false;
+        }
+
+        // Everything the object wrote is in object space
+        result.p_error = transform_point_error(&to_world, result.position, result.p_error);
+        result.position = to_world.transform_point(&result.position);
+        result.geom_normal = to_object.transform_normal(&result.geom_normal).normalized();
+        result.shading_normal = to_object.transform_normal(&result.shading_normal).normalized();
+        result.dpdu = to_world.transform_vector(&result.dpdu);
+        true
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        let (_, to_object) = self.transforms_at(ray.time);
+        self.object.occluded(&object_ray(&to_object, ray))
+    }
+
+    fn bbox(&self) -> Aabb {
+        let object_bbox = self.object.bbox();
+        let bbox = object_bbox.transformed(&self.to_world);
+
+        // Corners move linearly, so the bounds at both ends also bound everything in between
+        match self.end_to_world {
+            Some(ref end_to_world) => bbox.union(&object_bbox.transformed(end_to_world)),
+            None => bbox,
+        }
+    }
+
+    /// Lights don't move, so moving instances emit from their placement at shutter open.
+    /// Spheres, disks and cylinders can't emit under non-uniform scaling, which would distort
+    /// them into shapes no light represents.
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        let m = &self.to_world;
+        let (scale, mirrored) = uniform_scale(m);
+
+        let point = |p: Vec3f| m.transform_point(&p);
+        let vector = |v: Vec3f| m.transform_vector(&v);
+
+        let mut emitters = Vec::new();
+        for emitter in self.object.emitters(emissive).into_iter() {
+            // Mirroring flips the winding, but not the normals of intersections with the
+            // instance, so swap it back to keep emitting from the same side
+            let shape = match (emitter.shape, scale) {
+                (EmitterShape::Triangle(p0, p1, p2), _) => Some(if mirrored {
+                    EmitterShape::Triangle(point(p0), point(p2), point(p1))
+                } else {
+                    EmitterShape::Triangle(point(p0), point(p1), point(p2))
+                }),
+                (EmitterShape::Quad(p0, e1, e2), _) => Some(if mirrored {
+                    EmitterShape::Quad(point(p0), vector(e2), vector(e1))
+                } else {
+                    EmitterShape::Quad(point(p0), vector(e1), vector(e2))
+                }),
+                (EmitterShape::Sphere(center, radius), Some(s)) =>
+                    Some(EmitterShape::Sphere(point(center), radius * s)),
+                (EmitterShape::Disk(center, normal, radius), Some(s)) =>
+                    Some(EmitterShape::Disk(point(center), vector(normal), radius * s)),
+                (EmitterShape::Cylinder(base, axis, radius, height), Some(s)) =>
+                    Some(EmitterShape::Cylinder(point(base), vector(axis), radius * s,
+                                                height * s)),
+                _ => None,
+            };
+
+            match shape {
+                Some(shape) => emitters.push(Emitter {
+                    mesh_id: emitter.mesh_id,
+                    prim_id: emitter.prim_id,
+                    mat_id: emitter.mat_id,
+                    shape: shape,
+                }),
+                None => (),
+            }
+        }
+        emitters
+    }
+}
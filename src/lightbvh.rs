@@ -0,0 +1,543 @@
+use math::{Vec3f, Aabb, Mat4f, vec3, PI};
+use std::num::{Float, FloatMath};
+
+const NUM_SPLIT_BUCKETS: uint = 12;
+
+/// Conservative description of where a light is and in which directions it emits, so its
+/// contribution at a point can be bounded.
+#[deriving(Copy, Clone)]
+pub struct LightBounds {
+    pub bbox: Aabb,
+    /// Approximate emitted power.
+    pub phi: f32,
+    /// Central direction of the surface normals.
+    pub w: Vec3f,
+    /// Cosine of the angle around `w` containing all the normals.
+    pub cos_theta_o: f32,
+    /// Cosine of the angle beyond the normals in which light is still emitted.
+    pub cos_theta_e: f32,
+    /// Whether light is also emitted around `-w`.
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn union(&self, o: &LightBounds) -> LightBounds {
+        if self.phi == 0.0 {
+            return *o;
+        }
+        if o.phi == 0.0 {
+            return *self;
+        }
+
+        let (w, cos_theta_o) = union_cones(self.w, self.cos_theta_o, o.w, o.cos_theta_o);
+        LightBounds {
+            bbox: self.bbox.union(&o.bbox),
+            phi: self.phi + o.phi,
+            w: w,
+            cos_theta_o: cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(o.cos_theta_e),
+            two_sided: self.two_sided || o.two_sided,
+        }
+    }
+
+    /// Estimates the contribution of the bounded lights at `p`, for a surface with normal `n`.
+    /// A zero `n` leaves out the cosine at the receiver.
+    pub fn importance(&self, p: Vec3f, n: Vec3f) -> f32 {
+        let pc = self.bbox.centroid();
+        let to_p = p - pc;
+        // Avoid blowing up close to the lights
+        let dist_sqr = to_p.length_sqr().max(self.bbox.extent().length() * 0.5);
+
+        let (sin_theta_b, cos_theta_b) = self.subtended_cone(p);
+
+        let wi = if to_p.length_sqr() > 0.0 { to_p.normalized() } else { self.w };
+        let mut cos_theta_w = self.w.dot(wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Smallest angle between the emission cone and the direction to p, given the extent
+        // of the bounds
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / dist_sqr;
+
+        if n.length_sqr() > 0.0 {
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+
+        importance.max(0.0)
+    }
+
+    /// Sine and cosine of the half-angle of a cone from `p` containing the bounding box.
+    fn subtended_cone(&self, p: Vec3f) -> (f32, f32) {
+        let center = self.bbox.centroid();
+        let radius_sqr = (self.bbox.max - center).length_sqr();
+        let dist_sqr = (p - center).length_sqr();
+        if dist_sqr <= radius_sqr {
+            // Inside, light can come from anywhere
+            return (0.0, -1.0);
+        }
+
+        let sin_sqr = radius_sqr / dist_sqr;
+        (sin_sqr.sqrt(), safe_sqrt(1.0 - sin_sqr))
+    }
+
+    /// Heuristic cost of a node with these bounds, from its power, the solid angle it emits
+    /// into and its size.
+    fn cost(&self) -> f32 {
+        let theta_o = self.cos_theta_o.max(-1.0).min(1.0).acos();
+        let theta_e = self.cos_theta_e.max(-1.0).min(1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = theta_o.sin();
+
+        let m_omega = 2.0 * PI * (1.0 - self.cos_theta_o) +
+            PI / 2.0 * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() -
+                        2.0 * theta_o * sin_theta_o + self.cos_theta_o);
+        self.phi * m_omega * self.bbox.surface_area()
+    }
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+/// Cosine of `theta_a - theta_b`, or 1 if it's negative.
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+}
+
+/// Sine of `theta_a - theta_b`, or 0 if it's negative.
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 0.0 } else { sin_a * cos_b - cos_a * sin_b }
+}
+
+/// Smallest cone containing the cones around `w_a` and `w_b` with the given cosines of their
+/// half-angles.
+fn union_cones(w_a: Vec3f, cos_a: f32, w_b: Vec3f, cos_b: f32) -> (Vec3f, f32) {
+    let theta_a = cos_a.max(-1.0).min(1.0).acos();
+    let theta_b = cos_b.max(-1.0).min(1.0).acos();
+    let theta_d = w_a.dot(w_b).max(-1.0).min(1.0).acos();
+
+    // One cone may already contain the other
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (w_a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (w_b, cos_b);
+    }
+
+    let whole_sphere = (w_a, -1.0);
+    let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+    if theta_o >= PI {
+        return whole_sphere;
+    }
+
+    // Rotate w_a towards w_b to the center of the new cone
+    let theta_r = theta_o - theta_a;
+    let axis = w_a.cross(w_b);
+    if axis.length_sqr() == 0.0 {
+        return whole_sphere;
+    }
+    let w = Mat4f::rotate(&axis, theta_r * 180.0 / PI).transform_vector(&w_a);
+    (w, theta_o.cos())
+}
+
+struct LightBvhNode {
+    bounds: LightBounds,
+    /// For leaves, the light id. For interior nodes, the index of the second child. The first
+    /// child always immediately follows its parent.
+    offset: uint,
+    is_leaf: bool,
+    parent: Option<uint>,
+}
+
+/// Bounding volume hierarchy over lights with finite extent, used to pick lights with
+/// probability proportional to their estimated contribution at a point.
+pub struct LightBvh {
+    nodes: Vec<LightBvhNode>,
+    /// Leaf node of each light id, if it's in the hierarchy.
+    light_to_node: Vec<Option<uint>>,
+}
+
+impl LightBvh {
+    /// Builds the hierarchy from `(light_id, bounds)` pairs. Lights that don't emit are left
+    /// out. `num_lights` is the total number of light ids.
+    pub fn build(lights: Vec<(uint, LightBounds)>, num_lights: uint) -> LightBvh {
+        let mut lights: Vec<(uint, LightBounds)> =
+            lights.into_iter().filter(|&(_, ref b)| b.phi > 0.0).collect();
+
+        let mut bvh = LightBvh {
+            nodes: Vec::new(),
+            light_to_node: Vec::from_elem(num_lights, None),
+        };
+
+        if !lights.is_empty() {
+            bvh.build_recursive(lights[mut], None);
+        }
+
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn build_recursive(&mut self, lights: &mut [(uint, LightBounds)],
+                       parent: Option<uint>) -> uint {
+        let node_index = self.nodes.len();
+
+        if lights.len() == 1 {
+            let (light_id, bounds) = lights[0];
+            self.nodes.push(LightBvhNode {
+                bounds: bounds,
+                offset: light_id,
+                is_leaf: true,
+                parent: parent,
+            });
+            self.light_to_node[mut][light_id] = Some(node_index);
+            return node_index;
+        }
+
+        let (_, mut bounds) = lights[0];
+        for &(_, ref b) in lights[1..].iter() {
+            bounds = bounds.union(b);
+        }
+        self.nodes.push(LightBvhNode {
+            bounds: bounds,
+            offset: 0,
+            is_leaf: false,
+            parent: parent,
+        });
+
+        let mid = LightBvh::find_split(lights, &bounds);
+        let (left, right) = lights.split_at_mut(mid);
+        self.build_recursive(left, Some(node_index));
+        let second_child = self.build_recursive(right, Some(node_index));
+        self.nodes[mut][node_index].offset = second_child;
+
+        node_index
+    }
+
+    /// Partitions `lights` along the split with the lowest cost, returning the partition
+    /// point.
+    fn find_split(lights: &mut [(uint, LightBounds)], bounds: &LightBounds) -> uint {
+        let mut centroid_bounds = Aabb::empty();
+        for &(_, ref b) in lights.iter() {
+            centroid_bounds = centroid_bounds.grow(b.bbox.centroid());
+        }
+
+        let extent = bounds.bbox.extent();
+        let max_extent = extent.x.max(extent.y).max(extent.z);
+        let centroid_extent = centroid_bounds.extent();
+
+        let mut best: Option<(uint, uint)> = None;
+        let mut best_cost = 1e36f32;
+        for axis in range(0, 3u) {
+            if centroid_extent[axis] <= 0.0 {
+                continue;
+            }
+
+            let bin_scale = NUM_SPLIT_BUCKETS as f32 / centroid_extent[axis];
+            let mut bucket_bounds: Vec<Option<LightBounds>> =
+                Vec::from_elem(NUM_SPLIT_BUCKETS, None);
+            for &(_, ref b) in lights.iter() {
+                let bucket = bucket_of(b, &centroid_bounds, axis, bin_scale);
+                bucket_bounds[mut][bucket] = Some(match bucket_bounds[bucket] {
+                    Some(ref acc) => acc.union(b),
+                    None => *b,
+                });
+            }
+
+            // Splits along thin axes are penalized, as they separate orientations poorly
+            let kr = max_extent / extent[axis].max(1e-9);
+            for split in range(0, NUM_SPLIT_BUCKETS - 1) {
+                let left = merge_buckets(bucket_bounds[..split + 1]);
+                let right = merge_buckets(bucket_bounds[split + 1..]);
+                let cost = match (left, right) {
+                    (Some(l), Some(r)) => kr * (l.cost() + r.cost()),
+                    _ => continue,
+                };
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((axis, split));
+                }
+            }
+        }
+
+        let mid = match best {
+            Some((axis, split)) => {
+                let bin_scale = NUM_SPLIT_BUCKETS as f32 / centroid_extent[axis];
+                let mut mid = 0;
+                for i in range(0, lights.len()) {
+                    let (_, b) = lights[i];
+                    if bucket_of(&b, &centroid_bounds, axis, bin_scale) <= split {
+                        lights.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+                mid
+            },
+            None => 0,
+        };
+
+        if mid == 0 || mid == lights.len() { lights.len() / 2 } else { mid }
+    }
+
+    fn child_probabilities(&self, node_index: uint, p: Vec3f, n: Vec3f) -> Option<(f32, f32)> {
+        let node = &self.nodes[node_index];
+        let i0 = self.nodes[node_index + 1].bounds.importance(p, n);
+        let i1 = self.nodes[node.offset].bounds.importance(p, n);
+        if i0 == 0.0 && i1 == 0.0 {
+            return None;
+        }
+        let p0 = i0 / (i0 + i1);
+        Some((p0, 1.0 - p0))
+    }
+
+    /// Picks a light for shading point `p` with normal `n`, which may be zero. Returns the
+    /// light id and the probability of picking it, or `None` if no light contributes.
+    pub fn sample(&self, p: Vec3f, n: Vec3f, u: f32) -> Option<(uint, f32)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = u;
+        let mut node_index = 0u;
+        let mut pmf = 1.0f32;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.is_leaf {
+                // A single light still has to contribute at all
+                if node_index > 0 || node.bounds.importance(p, n) > 0.0 {
+                    return Some((node.offset, pmf));
+                }
+                return None;
+            }
+
+            let (p0, p1) = match self.child_probabilities(node_index, p, n) {
+                Some(probs) => probs,
+                None => return None,
+            };
+            if u < p0 {
+                node_index += 1;
+                u = (u / p0).min(0.99999994);
+                pmf *= p0;
+            } else {
+                node_index = node.offset;
+                u = ((u - p0) / p1).min(0.99999994);
+                pmf *= p1;
+            }
+        }
+    }
+
+    /// Probability of `sample` returning `light_id` for shading point `p` with normal `n`.
+    pub fn pdf(&self, p: Vec3f, n: Vec3f, light_id: uint) -> f32 {
+        let leaf = match self.light_to_node.get(light_id) {
+            Some(&Some(leaf)) => leaf,
+            _ => return 0.0,
+        };
+
+        if leaf == 0 {
+            return if self.nodes[0].bounds.importance(p, n) > 0.0 { 1.0 } else { 0.0 };
+        }
+
+        // Walk up to the root, multiplying the probabilities of the branches taken
+        let mut pmf = 1.0f32;
+        let mut node_index = leaf;
+        loop {
+            let parent = match self.nodes[node_index].parent {
+                Some(parent) => parent,
+                None => return pmf,
+            };
+            let (p0, p1) = match self.child_probabilities(parent, p, n) {
+                Some(probs) => probs,
+                None => return 0.0,
+            };
+            pmf *= if node_index == parent + 1 { p0 } else { p1 };
+            node_index = parent;
+        }
+    }
+}
+
+fn bucket_of(bounds: &LightBounds, centroid_bounds: &Aabb, axis: uint, bin_scale: f32) -> uint {
+    let bucket = ((bounds.bbox.centroid()[axis] - centroid_bounds.min[axis]) * bin_scale) as uint;
+    if bucket < NUM_SPLIT_BUCKETS { bucket } else { NUM_SPLIT_BUCKETS - 1 }
+}
+
+fn merge_buckets(buckets: &[Option<LightBounds>]) -> Option<LightBounds> {
+    let mut res: Option<LightBounds> = None;
+    for bucket in buckets.iter() {
+        res = match (res, *bucket) {
+            (Some(acc), Some(b)) => Some(acc.union(&b)),
+            (None, b) => b,
+            (acc, None) => acc,
+        };
+    }
+    res
+}
+
+/// Bounds of a light emitting from a point in all directions.
+pub fn point_bounds(position: Vec3f, phi: f32) -> LightBounds {
+    LightBounds {
+        bbox: Aabb::from_point(position),
+        phi: phi,
+        w: vec3(0.0, 0.0, 1.0),
+        cos_theta_o: -1.0,
+        cos_theta_e: 0.0,
+        two_sided: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use camera::CameraSettings;
+    use lights::{AbstractLight, SceneSphere, AreaLight, SphereLight, PointLight, SpotLight,
+                 LightSelection, EmissionMode};
+    use math::{Vec3f, vec2, vec3, vec3s};
+    use scene::{Scene, LIGHT_POINT, LIGHT_BACKGROUND};
+    use std::num::Float;
+    use super::LightBvh;
+
+    const NUM_PICKS: uint = 64;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-5 * a.abs().max(b.abs()).max(1.0),
+                "{} differs from {}", a, b);
+    }
+
+    /// Lights above the floor around the origin, all reaching every point in `shading_points`.
+    fn lights() -> Vec<Box<AbstractLight + Sync + 'static>> {
+        let mut lights: Vec<Box<AbstractLight + Sync + 'static>> = Vec::new();
+
+        let mut point = box PointLight::new(vec3(0.0, 0.0, 2.5));
+        point.intensity = vec3s(1.0);
+        lights.push(point);
+
+        let mut spot = box SpotLight::new(vec3(1.5, 0.0, 3.0), vec3(0.0, 0.0, -1.0), 45.0, 60.0);
+        spot.intensity = vec3s(5.0);
+        lights.push(spot);
+
+        let mut spot = box SpotLight::new(vec3(-1.5, 1.0, 3.0), vec3(0.3, 0.0, -1.0), 30.0, 50.0);
+        spot.intensity = vec3s(3.0);
+        lights.push(spot);
+
+        // Facing down
+        let mut area = box AreaLight::new(vec3(-1.0, -1.0, 3.0), vec3(0.0, 1.0, 3.0),
+                                          vec3(1.0, -1.0, 3.0));
+        area.intensity = vec3s(2.0);
+        lights.push(area);
+
+        let mut two_sided = box AreaLight::new(vec3(-2.5, -1.0, 0.0), vec3(-2.5, 1.0, 0.0),
+                                               vec3(-2.5, 0.0, 2.0));
+        two_sided.intensity = vec3s(1.5);
+        two_sided.emission_mode = EmissionMode::Both;
+        lights.push(two_sided);
+
+        let mut sphere = box SphereLight::new(vec3(1.5, 2.0, 1.5), 0.3);
+        sphere.intensity = vec3s(4.0);
+        lights.push(sphere);
+
+        lights
+    }
+
+    /// Positions and normals, including a zero normal which leaves out the receiver cosine.
+    fn shading_points() -> Vec<(Vec3f, Vec3f)> {
+        vec![
+            (vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)),
+            (vec3(1.0, -1.0, 0.5), vec3(0.0, 0.0, 1.0)),
+            (vec3(-1.5, 1.0, 0.5), vec3s(0.0)),
+            (vec3(0.5, 0.5, 1.0), vec3(0.6, 0.0, 0.8)),
+        ]
+    }
+
+    fn build(lights: &[Box<AbstractLight + Sync + 'static>]) -> LightBvh {
+        let scene_sphere = SceneSphere {
+            scene_center: vec3s(0.0), scene_radius: 10.0, inv_scene_radius_sqr: 0.01 };
+        let bounds = lights.iter().enumerate()
+            .map(|(i, l)| (i, l.bounds(&scene_sphere).unwrap())).collect();
+        LightBvh::build(bounds, lights.len())
+    }
+
+    #[test]
+    fn sample_pmf_matches_pdf() {
+        let lights = lights();
+        let bvh = build(lights[]);
+        for &(p, n) in shading_points().iter() {
+            for i in range(0, NUM_PICKS) {
+                let u = (i as f32 + 0.5) / NUM_PICKS as f32;
+                match bvh.sample(p, n, u) {
+                    Some((light_id, pmf)) => assert_close(pmf, bvh.pdf(p, n, light_id)),
+                    None => panic!("No light picked at {}", p),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pdfs_sum_to_one() {
+        let lights = lights();
+        let bvh = build(lights[]);
+        for &(p, n) in shading_points().iter() {
+            let total = range(0, lights.len()).fold(0.0, |acc, i| acc + bvh.pdf(p, n, i));
+            assert_close(total, 1.0);
+        }
+    }
+
+    #[test]
+    fn no_pick_outside_spot_cone() {
+        let mut spot = box SpotLight::new(vec3(0.0, 0.0, 3.0), vec3(0.0, 0.0, -1.0), 20.0, 30.0);
+        spot.intensity = vec3s(1.0);
+        let mut lights: Vec<Box<AbstractLight + Sync + 'static>> = Vec::new();
+        lights.push(spot);
+        let bvh = build(lights[]);
+
+        let below = vec3(0.0, 0.0, 0.0);
+        assert!(bvh.sample(below, vec3s(0.0), 0.5) == Some((0, 1.0)));
+        assert_close(bvh.pdf(below, vec3s(0.0), 0), 1.0);
+
+        let beside = vec3(3.0, 0.0, 2.5);
+        assert!(bvh.sample(beside, vec3s(0.0), 0.5).is_none());
+        assert_eq!(bvh.pdf(beside, vec3s(0.0), 0), 0.0);
+    }
+
+    /// Checks `light_pick_pdf` against `pick_light`, with the background as an infinite light
+    /// mixed with the light BVH holding a spot light aimed at the floor.
+    #[test]
+    fn scene_light_pick_pdf_with_infinite_lights() {
+        let mut scene = Scene::load_cornell_box(vec2(16, 16), LIGHT_POINT | LIGHT_BACKGROUND,
+                                                &CameraSettings::new());
+        scene.build_scene_sphere();
+        scene.set_spot_light(20.0, 30.0);
+        scene.set_light_selection(LightSelection::Bvh);
+
+        let num_lights = scene.get_light_count();
+        // On the floor in the cone, where both can be picked, and in a top corner outside it,
+        // where only the background can
+        let points = [(vec3(0.0, -0.5, -1.2), vec3(0.0, 0.0, 1.0), 1.0),
+                      (vec3(1.2, 1.2, 1.2), vec3(0.0, 0.0, -1.0), 0.5)];
+        for &(p, n, expected_total) in points.iter() {
+            let total = range(0, num_lights)
+                .fold(0.0, |acc, i| acc + scene.light_pick_pdf(p, n, i as int));
+            assert_close(total, expected_total);
+
+            for i in range(0, NUM_PICKS) {
+                let u = (i as f32 + 0.5) / NUM_PICKS as f32;
+                match scene.pick_light(p, n, u) {
+                    Some((light_id, prob)) =>
+                        assert_close(prob, scene.light_pick_pdf(p, n, light_id)),
+                    None => assert!(expected_total < 1.0),
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,15 @@
-use math::{Vec3f, vec3, vec3s};
+use math::{Vec2f, Vec3f, Aabb, vec2, vec3, vec3s, PI, INV_PI};
 use frame::Frame;
+use framebuffer::Framebuffer;
+use geometry::AbstractShape;
+use distribution::Distribution2D;
+use lightbvh::{LightBounds, point_bounds};
+use sky::{PreethamSky, SUN_ANGULAR_RADIUS};
+use utils::{luminance, sample_uniform_triangle, sample_cos_hemisphere_w,
+            sample_uniform_sphere_w, uniform_sphere_pdf_w, sample_uniform_cone_w,
+            uniform_cone_pdf_w, sample_concentric_disc, concentric_disc_pdf_a, pdf_w_to_a,
+            EPS_COSINE};
+use std::num::{Float, FloatMath};
 
 pub struct SceneSphere {
     pub scene_center: Vec3f,
@@ -7,8 +17,235 @@ pub struct SceneSphere {
     pub inv_scene_radius_sqr: f32,
 }
 
+/// Result of sampling a point on a light for direct illumination.
+pub struct Illumination {
+    pub radiance: Vec3f,
+    pub dir_to_light: Vec3f,
+    pub distance: f32,
+    /// Pdf of the sampled direction with respect to solid angle at the receiving point.
+    pub direct_pdf_w: f32,
+    /// Pdf of emitting a particle along the same path, for MIS.
+    pub emission_pdf_w: f32,
+    pub cos_at_light: f32,
+}
+
+/// A particle emitted by a light.
+pub struct Emission {
+    pub energy: Vec3f,
+    pub position: Vec3f,
+    pub direction: Vec3f,
+    /// Pdf of the position and direction, with respect to area times solid angle.
+    pub emission_pdf_w: f32,
+    /// Pdf of `illuminate` choosing the same position, with respect to area. Lights sampling
+    /// relative to the receiving point leave it at 0, see `AbstractLight::receiver_direct_pdf_a`.
+    pub direct_pdf_a: f32,
+    /// Only used for finite, non-delta lights.
+    pub cos_theta_light: f32,
+}
+
+/// Radiance of a light hit by a ray.
+pub struct Radiance {
+    pub radiance: Vec3f,
+    /// Pdf of `illuminate` choosing the hit point. For background lights it's with respect to
+    /// solid angle instead of area.
+    pub direct_pdf_a: f32,
+    pub emission_pdf_w: f32,
+}
+
 pub trait AbstractLight {
-    // TODO
+    /// Samples a point on the light illuminating `receiving_position`. Returns `None` if the
+    /// sampled point doesn't emit towards it.
+    fn illuminate(&self, scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination>;
+
+    /// Samples a particle leaving the light.
+    fn emit(&self, scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission;
+
+    /// Returns the radiance along a ray from `ray_origin` hitting the light at `hit_point`, or
+    /// `None` if it doesn't emit in that direction.
+    fn get_radiance(&self, scene_sphere: &SceneSphere, ray_origin: Vec3f, ray_direction: Vec3f,
+                    hit_point: Vec3f) -> Option<Radiance>;
+
+    /// Pdf of `illuminate` from `receiving_position` choosing `light_point`, with respect to
+    /// area, for lights that sample relative to the receiving point. `None` if it doesn't depend
+    /// on it, in which case the `direct_pdf_a` of `emit` holds.
+    fn receiver_direct_pdf_a(&self, _light_point: Vec3f,
+                             _receiving_position: Vec3f) -> Option<f32> {
+        None
+    }
+
+    /// Whether the light has a finite extent, unlike directional and background lights.
+    fn is_finite(&self) -> bool;
+
+    /// Whether the light can't be hit by rays.
+    fn is_delta(&self) -> bool;
+
+    /// Approximate emitted power (luminance of the flux), used to decide how often to sample
+    /// the light.
+    fn power(&self, scene_sphere: &SceneSphere) -> f32;
+
+    /// Spatial and directional bounds of the emission for the light BVH, or `None` for
+    /// infinite lights.
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds>;
+}
+
+/// How to choose which light to sample.
+#[deriving(Copy)]
+pub enum LightSelection {
+    Uniform,
+    /// Proportionally to `AbstractLight::power`, uniformly if no light has any.
+    Power,
+    /// By estimated contribution at the shading point, using a light BVH. Infinite lights are
+    /// picked uniformly, as often as the whole BVH.
+    Bvh,
+}
+
+impl LightSelection {
+    pub fn get_name(self) -> &'static str {
+        match self {
+            LightSelection::Uniform => "uniform",
+            LightSelection::Power => "power",
+            LightSelection::Bvh => "bvh",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<LightSelection> {
+        Some(match s {
+            "uniform" => LightSelection::Uniform,
+            "power" => LightSelection::Power,
+            "bvh" => LightSelection::Bvh,
+            _ => return None,
+        })
+    }
+}
+
+/// Samples a point on the disc perpendicular to `direction` that covers the scene sphere, from
+/// where particles of infinite lights start.
+fn sample_scene_disc(scene_sphere: &SceneSphere, direction: Vec3f, pos_rnd: Vec2f) -> Vec3f {
+    let xy = sample_concentric_disc(pos_rnd);
+    let frame = Frame::from_z(direction);
+
+    scene_sphere.scene_center + (-direction + frame.binormal() * vec3s(xy.x) +
+                                 frame.tangent() * vec3s(xy.y)) * vec3s(scene_sphere.scene_radius)
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Spherical maps
+
+/// Directional function stored as an equirectangular (latitude-longitude) image around the z
+/// axis of `frame`, with the top row along +z. Directions are importance sampled according to
+/// the image luminance.
+struct SphericalMap {
+    image: Framebuffer,
+    res_x: uint,
+    res_y: uint,
+    frame: Frame,
+    distribution: Distribution2D,
+}
+
+impl SphericalMap {
+    fn new(image: Framebuffer, frame: Frame) -> SphericalMap {
+        let resolution = image.resolution();
+        let (res_x, res_y) = (resolution.x as uint, resolution.y as uint);
+
+        // Rows near the poles cover a smaller solid angle
+        let mut weights = Vec::with_capacity(res_x * res_y);
+        for y in range(0, res_y) {
+            let sin_theta = ((y as f32 + 0.5) / res_y as f32 * PI).sin();
+            for x in range(0, res_x) {
+                weights.push(luminance(image.pixels()[x + y * res_x]) * sin_theta);
+            }
+        }
+
+        SphericalMap {
+            distribution: Distribution2D::new(weights[], res_x, res_y),
+            image: image,
+            res_x: res_x,
+            res_y: res_y,
+            frame: frame,
+        }
+    }
+
+    fn direction_to_uv(&self, direction: Vec3f) -> Vec2f {
+        let local = self.frame.to_local(direction);
+        let theta = local.z.max(-1.0).min(1.0).acos();
+        let mut phi = local.y.atan2(local.x);
+        if phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+        vec2(phi / (2.0 * PI), theta / PI)
+    }
+
+    fn uv_to_direction(&self, uv: Vec2f) -> Vec3f {
+        let (sin_theta, cos_theta) = (uv.y * PI).sin_cos();
+        let (sin_phi, cos_phi) = (uv.x * 2.0 * PI).sin_cos();
+        self.frame.to_world(vec3(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta))
+    }
+
+    fn lookup(&self, uv: Vec2f) -> Vec3f {
+        let x = ((uv.x * self.res_x as f32) as uint).min(self.res_x - 1);
+        let y = ((uv.y * self.res_y as f32) as uint).min(self.res_y - 1);
+        self.image.pixels()[x + y * self.res_x]
+    }
+
+    /// Converts the pdf of the map position `uv` to solid angle.
+    fn pdf_w(&self, uv: Vec2f, map_pdf: f32) -> f32 {
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        map_pdf / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Samples a direction, returning it with the map value and the solid angle pdf.
+    fn sample(&self, rnd: Vec2f) -> Option<(Vec3f, Vec3f, f32)> {
+        let (uv, map_pdf) = self.distribution.sample_continuous(rnd);
+        let pdf_w = self.pdf_w(uv, map_pdf);
+        if pdf_w == 0.0 {
+            return None;
+        }
+        Some((self.uv_to_direction(uv), self.lookup(uv), pdf_w))
+    }
+
+    /// Integral of the luminance over the sphere of directions.
+    fn integral(&self) -> f32 {
+        self.distribution.integral() * 2.0 * PI * PI
+    }
+
+    /// Returns the map value in `direction` and the pdf of sampling it.
+    fn evaluate(&self, direction: Vec3f) -> (Vec3f, f32) {
+        let uv = self.direction_to_uv(direction);
+        (self.lookup(uv), self.pdf_w(uv, self.distribution.pdf(uv)))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Area light
+
+/// Which sides of an area light emit, relative to the normal given by the vertex winding.
+#[deriving(Copy, PartialEq)]
+pub enum EmissionMode {
+    Front,
+    Back,
+    Both,
+}
+
+impl EmissionMode {
+    /// Cosine between the normal and the outgoing direction on the emitting side, or `None` if
+    /// no emitting side faces it.
+    fn emitting_cos(self, cos_normal: f32) -> Option<f32> {
+        let cos = match self {
+            EmissionMode::Front => cos_normal,
+            EmissionMode::Back => -cos_normal,
+            EmissionMode::Both => cos_normal.abs(),
+        };
+        if cos > 0.0 { Some(cos) } else { None }
+    }
+
+    /// Probability of emitting from one given side.
+    fn side_prob(self) -> f32 {
+        if self == EmissionMode::Both { 0.5 } else { 1.0 }
+    }
 }
 
 pub struct AreaLight {
@@ -17,10 +254,12 @@ pub struct AreaLight {
     e2: Vec3f,
     frame: Frame,
     pub intensity: Vec3f,
+    pub emission_mode: EmissionMode,
     inv_area: f32,
 }
 
 impl AreaLight {
+    /// The front side faces `(p1 - p0) x (p2 - p0)`, and is the only one emitting by default.
     pub fn new(p0: Vec3f, p1: Vec3f, p2: Vec3f) -> AreaLight {
         let e1 = p1 - p0;
         let e2 = p2 - p0;
@@ -30,17 +269,386 @@ impl AreaLight {
             p0: p0,
             e1: e1,
             e2: e2,
-            frame: Frame::identity(),
+            frame: Frame::from_z(normal),
             intensity: vec3s(0.0),
+            emission_mode: EmissionMode::Front,
             inv_area: 2.0 / len,
         }
     }
 }
 
 impl AbstractLight for AreaLight {
-    // TODO
+    fn illuminate(&self, _scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let uv = sample_uniform_triangle(rnd);
+        let light_point = self.p0 + self.e1 * vec3s(uv.x) + self.e2 * vec3s(uv.y);
+
+        let to_light = light_point - receiving_position;
+        let dist_sqr = to_light.length_sqr();
+        let distance = dist_sqr.sqrt();
+        let dir_to_light = to_light / vec3s(distance);
+
+        let cos_normal_dir = match self.emission_mode.emitting_cos(
+                self.frame.normal().dot(-dir_to_light)) {
+            Some(cos) if cos >= EPS_COSINE => cos,
+            _ => return None,
+        };
+
+        Some(Illumination {
+            radiance: self.intensity,
+            dir_to_light: dir_to_light,
+            distance: distance,
+            direct_pdf_w: self.inv_area * dist_sqr / cos_normal_dir,
+            emission_pdf_w: self.inv_area * cos_normal_dir * (1.0 / PI) *
+                            self.emission_mode.side_prob(),
+            cos_at_light: cos_normal_dir,
+        })
+    }
+
+    fn emit(&self, _scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        let uv = sample_uniform_triangle(pos_rnd);
+        let position = self.p0 + self.e1 * vec3s(uv.x) + self.e2 * vec3s(uv.y);
+
+        // Pick the side first, reusing the random number for two-sided lights
+        let (back, dir_rnd) = match self.emission_mode {
+            EmissionMode::Front => (false, dir_rnd),
+            EmissionMode::Back => (true, dir_rnd),
+            EmissionMode::Both => if dir_rnd.x < 0.5 {
+                (false, vec2(dir_rnd.x * 2.0, dir_rnd.y))
+            } else {
+                (true, vec2((dir_rnd.x * 2.0 - 1.0).min(0.99999994), dir_rnd.y))
+            },
+        };
+
+        let (mut local_dir_out, pdf_w) = sample_cos_hemisphere_w(dir_rnd);
+        // The particle can't be rejected, so just bias it to a valid angle
+        local_dir_out.z = local_dir_out.z.max(EPS_COSINE);
+        let cos_theta_light = local_dir_out.z;
+        if back {
+            local_dir_out.z = -local_dir_out.z;
+        }
+
+        Emission {
+            energy: self.intensity * vec3s(cos_theta_light),
+            position: position,
+            direction: self.frame.to_world(local_dir_out),
+            emission_pdf_w: pdf_w * self.inv_area * self.emission_mode.side_prob(),
+            direct_pdf_a: self.inv_area,
+            cos_theta_light: cos_theta_light,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        let cos_out_l = match self.emission_mode.emitting_cos(
+                self.frame.normal().dot(-ray_direction)) {
+            Some(cos) => cos,
+            None => return None,
+        };
+
+        Some(Radiance {
+            radiance: self.intensity,
+            direct_pdf_a: self.inv_area,
+            emission_pdf_w: cos_out_l * (1.0 / PI) * self.inv_area *
+                            self.emission_mode.side_prob(),
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, _scene_sphere: &SceneSphere) -> f32 {
+        let sides = if self.emission_mode == EmissionMode::Both { 2.0 } else { 1.0 };
+        luminance(self.intensity) * PI / self.inv_area * sides
+    }
+
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        let normal = self.frame.normal();
+        Some(LightBounds {
+            bbox: Aabb::from_point(self.p0).grow(self.p0 + self.e1).grow(self.p0 + self.e2),
+            phi: self.power(scene_sphere),
+            w: if self.emission_mode == EmissionMode::Back { -normal } else { normal },
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: self.emission_mode == EmissionMode::Both,
+        })
+    }
 }
 
+//////////////////////////////////////////////////////////////////////////
+// Sphere light
+
+/// Sphere emitting from its outside. Direct illumination samples the cone of directions the
+/// sphere subtends instead of its area, which wastes no samples on the far side.
+pub struct SphereLight {
+    center: Vec3f,
+    radius: f32,
+    pub intensity: Vec3f,
+    inv_area: f32,
+}
+
+impl SphereLight {
+    pub fn new(center: Vec3f, radius: f32) -> SphereLight {
+        SphereLight {
+            center: center,
+            radius: radius,
+            intensity: vec3s(0.0),
+            inv_area: 1.0 / (4.0 * PI * radius * radius),
+        }
+    }
+
+    /// Cosine of the half-angle of the cone subtended by the sphere from `position`, or `None`
+    /// if it's inside.
+    fn cone_cos_max(&self, position: Vec3f) -> Option<f32> {
+        let dist_sqr = (self.center - position).length_sqr();
+        let radius_sqr = self.radius * self.radius;
+        if dist_sqr <= radius_sqr {
+            return None;
+        }
+        Some((1.0 - radius_sqr / dist_sqr).max(0.0).sqrt())
+    }
+}
+
+impl AbstractLight for SphereLight {
+    fn illuminate(&self, _scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let cos_max = match self.cone_cos_max(receiving_position) {
+            Some(cos_max) => cos_max,
+            None => return None,
+        };
+
+        let to_center = self.center - receiving_position;
+        let dist_center = to_center.length();
+        let (local_dir, direct_pdf_w) = sample_uniform_cone_w(rnd, cos_max);
+        let dir_to_light = Frame::from_z(to_center).to_world(local_dir);
+
+        // Closest intersection of the sampled direction with the sphere
+        let sin_theta_sqr = 1.0 - local_dir.z * local_dir.z;
+        let distance = dist_center * local_dir.z -
+            (self.radius * self.radius - dist_center * dist_center * sin_theta_sqr).max(0.0).sqrt();
+        let normal = (receiving_position + dir_to_light * vec3s(distance) - self.center) /
+                     vec3s(self.radius);
+
+        let cos_normal_dir = normal.dot(-dir_to_light);
+        if cos_normal_dir < EPS_COSINE {
+            return None;
+        }
+
+        Some(Illumination {
+            radiance: self.intensity,
+            dir_to_light: dir_to_light,
+            distance: distance,
+            direct_pdf_w: direct_pdf_w,
+            emission_pdf_w: self.inv_area * cos_normal_dir * (1.0 / PI),
+            cos_at_light: cos_normal_dir,
+        })
+    }
+
+    fn emit(&self, _scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        let (normal, _) = sample_uniform_sphere_w(pos_rnd);
+        let position = self.center + normal * vec3s(self.radius);
+
+        let (mut local_dir_out, pdf_w) = sample_cos_hemisphere_w(dir_rnd);
+        // The particle can't be rejected, so just bias it to a valid angle
+        local_dir_out.z = local_dir_out.z.max(EPS_COSINE);
+
+        Emission {
+            energy: self.intensity * vec3s(local_dir_out.z),
+            position: position,
+            direction: Frame::from_z(normal).to_world(local_dir_out),
+            emission_pdf_w: pdf_w * self.inv_area,
+            // The cone pdf depends on the receiving point, see `receiver_direct_pdf_a`
+            direct_pdf_a: 0.0,
+            cos_theta_light: local_dir_out.z,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, ray_origin: Vec3f,
+                    ray_direction: Vec3f, hit_point: Vec3f) -> Option<Radiance> {
+        let normal = (hit_point - self.center) / vec3s(self.radius);
+        let cos_out_l = normal.dot(-ray_direction);
+        if cos_out_l <= 0.0 {
+            return None;
+        }
+
+        let cos_max = match self.cone_cos_max(ray_origin) {
+            Some(cos_max) => cos_max,
+            None => return None,
+        };
+        let dist = (hit_point - ray_origin).length();
+
+        Some(Radiance {
+            radiance: self.intensity,
+            direct_pdf_a: pdf_w_to_a(uniform_cone_pdf_w(cos_max), dist, cos_out_l),
+            emission_pdf_w: cos_out_l * (1.0 / PI) * self.inv_area,
+        })
+    }
+
+    fn receiver_direct_pdf_a(&self, light_point: Vec3f,
+                             receiving_position: Vec3f) -> Option<f32> {
+        let to_receiver = receiving_position - light_point;
+        let dist = to_receiver.length();
+        let normal = (light_point - self.center) / vec3s(self.radius);
+        let cos_light = normal.dot(to_receiver) / dist;
+
+        Some(match self.cone_cos_max(receiving_position) {
+            Some(cos_max) if cos_light > 0.0 =>
+                pdf_w_to_a(uniform_cone_pdf_w(cos_max), dist, cos_light),
+            _ => 0.0,
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, _scene_sphere: &SceneSphere) -> f32 {
+        luminance(self.intensity) * PI / self.inv_area
+    }
+
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        Some(LightBounds {
+            bbox: Aabb::new(self.center - vec3s(self.radius), self.center + vec3s(self.radius)),
+            ..point_bounds(self.center, self.power(scene_sphere))
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Shape light
+
+/// Emits from a disk, quad or cylinder, sampling its area uniformly.
+pub struct ShapeLight {
+    shape: Box<AbstractShape + Sync + Send + 'static>,
+    pub intensity: Vec3f,
+    pub emission_mode: EmissionMode,
+    inv_area: f32,
+}
+
+impl ShapeLight {
+    /// The front side faces the shape's normal, and is the only one emitting by default.
+    pub fn new(shape: Box<AbstractShape + Sync + Send + 'static>) -> ShapeLight {
+        let inv_area = 1.0 / shape.area();
+        ShapeLight {
+            shape: shape,
+            intensity: vec3s(0.0),
+            emission_mode: EmissionMode::Front,
+            inv_area: inv_area,
+        }
+    }
+}
+
+impl AbstractLight for ShapeLight {
+    fn illuminate(&self, _scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let sample = self.shape.sample(rnd);
+
+        let to_light = sample.position - receiving_position;
+        let dist_sqr = to_light.length_sqr();
+        let distance = dist_sqr.sqrt();
+        let dir_to_light = to_light / vec3s(distance);
+
+        let cos_normal_dir = match self.emission_mode.emitting_cos(
+                sample.normal.dot(-dir_to_light)) {
+            Some(cos) if cos >= EPS_COSINE => cos,
+            _ => return None,
+        };
+
+        Some(Illumination {
+            radiance: self.intensity,
+            dir_to_light: dir_to_light,
+            distance: distance,
+            direct_pdf_w: self.inv_area * dist_sqr / cos_normal_dir,
+            emission_pdf_w: self.inv_area * cos_normal_dir * (1.0 / PI) *
+                            self.emission_mode.side_prob(),
+            cos_at_light: cos_normal_dir,
+        })
+    }
+
+    fn emit(&self, _scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        let sample = self.shape.sample(pos_rnd);
+
+        // Pick the side first, reusing the random number for two-sided lights
+        let (back, dir_rnd) = match self.emission_mode {
+            EmissionMode::Front => (false, dir_rnd),
+            EmissionMode::Back => (true, dir_rnd),
+            EmissionMode::Both => if dir_rnd.x < 0.5 {
+                (false, vec2(dir_rnd.x * 2.0, dir_rnd.y))
+            } else {
+                (true, vec2((dir_rnd.x * 2.0 - 1.0).min(0.99999994), dir_rnd.y))
+            },
+        };
+
+        let (mut local_dir_out, pdf_w) = sample_cos_hemisphere_w(dir_rnd);
+        // The particle can't be rejected, so just bias it to a valid angle
+        local_dir_out.z = local_dir_out.z.max(EPS_COSINE);
+        let cos_theta_light = local_dir_out.z;
+        if back {
+            local_dir_out.z = -local_dir_out.z;
+        }
+
+        Emission {
+            energy: self.intensity * vec3s(cos_theta_light),
+            position: sample.position,
+            direction: Frame::from_z(sample.normal).to_world(local_dir_out),
+            emission_pdf_w: pdf_w * self.inv_area * self.emission_mode.side_prob(),
+            direct_pdf_a: self.inv_area,
+            cos_theta_light: cos_theta_light,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    ray_direction: Vec3f, hit_point: Vec3f) -> Option<Radiance> {
+        let cos_out_l = match self.emission_mode.emitting_cos(
+                self.shape.normal(hit_point).dot(-ray_direction)) {
+            Some(cos) => cos,
+            None => return None,
+        };
+
+        Some(Radiance {
+            radiance: self.intensity,
+            direct_pdf_a: self.inv_area,
+            emission_pdf_w: cos_out_l * (1.0 / PI) * self.inv_area *
+                            self.emission_mode.side_prob(),
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, _scene_sphere: &SceneSphere) -> f32 {
+        let sides = if self.emission_mode == EmissionMode::Both { 2.0 } else { 1.0 };
+        luminance(self.intensity) * PI / self.inv_area * sides
+    }
+
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        // Conservatively emitting in all directions, as cylinders have no single normal, which
+        // covers every emission mode
+        let bbox = self.shape.bbox();
+        Some(LightBounds {
+            bbox: bbox,
+            ..point_bounds(bbox.centroid(), self.power(scene_sphere))
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Directional light
+
 pub struct DirectionalLight {
     frame: Frame,
     pub intensity: Vec3f,
@@ -56,12 +664,60 @@ impl DirectionalLight {
 }
 
 impl AbstractLight for DirectionalLight {
-    // TODO
+    fn illuminate(&self, scene_sphere: &SceneSphere, _receiving_position: Vec3f,
+                  _rnd: Vec2f) -> Option<Illumination> {
+        Some(Illumination {
+            radiance: self.intensity,
+            dir_to_light: -self.frame.normal(),
+            distance: 1e36,
+            direct_pdf_w: 1.0,
+            emission_pdf_w: concentric_disc_pdf_a() * scene_sphere.inv_scene_radius_sqr,
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, scene_sphere: &SceneSphere, _dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        Emission {
+            energy: self.intensity,
+            position: sample_scene_disc(scene_sphere, self.frame.normal(), pos_rnd),
+            direction: self.frame.normal(),
+            emission_pdf_w: concentric_disc_pdf_a() * scene_sphere.inv_scene_radius_sqr,
+            direct_pdf_a: 1.0,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    _ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        None
+    }
+
+    fn is_finite(&self) -> bool {
+        false
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn power(&self, scene_sphere: &SceneSphere) -> f32 {
+        // Flux through the disc covering the scene
+        luminance(self.intensity) * PI * scene_sphere.scene_radius * scene_sphere.scene_radius
+    }
+
+    fn bounds(&self, _scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        None
+    }
 }
 
+//////////////////////////////////////////////////////////////////////////
+// Point light
+
 pub struct PointLight {
     position: Vec3f,
     pub intensity: Vec3f,
+    /// Modulates `intensity` by direction. Emits uniformly if `None`.
+    profile: Option<SphericalMap>,
 }
 
 impl PointLight {
@@ -69,14 +725,226 @@ impl PointLight {
         PointLight {
             position: position,
             intensity: vec3s(0.0),
+            profile: None,
         }
     }
+
+    /// Modulates the intensity by an equirectangular image, whose top row faces `axis`. IES
+    /// profiles are loaded this way, with `axis` pointing at the nadir of the luminaire.
+    pub fn set_profile(&mut self, profile: Framebuffer, axis: Vec3f) {
+        self.profile = Some(SphericalMap::new(profile, Frame::from_z(axis)));
+    }
 }
 
 impl AbstractLight for PointLight {
-    // TODO
+    fn illuminate(&self, _scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  _rnd: Vec2f) -> Option<Illumination> {
+        let to_light = self.position - receiving_position;
+        let dist_sqr = to_light.length_sqr();
+        let distance = dist_sqr.sqrt();
+        let dir_to_light = to_light / vec3s(distance);
+
+        let (radiance, emission_pdf_w) = match self.profile {
+            Some(ref profile) => {
+                let (value, pdf_w) = profile.evaluate(-dir_to_light);
+                if pdf_w == 0.0 {
+                    return None;
+                }
+                (self.intensity * value, pdf_w)
+            },
+            None => (self.intensity, uniform_sphere_pdf_w()),
+        };
+
+        Some(Illumination {
+            radiance: radiance,
+            dir_to_light: dir_to_light,
+            distance: distance,
+            direct_pdf_w: dist_sqr,
+            emission_pdf_w: emission_pdf_w,
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, _scene_sphere: &SceneSphere, dir_rnd: Vec2f, _pos_rnd: Vec2f) -> Emission {
+        let (direction, energy, pdf_w) = match self.profile {
+            Some(ref profile) => match profile.sample(dir_rnd) {
+                Some((direction, value, pdf_w)) => (direction, self.intensity * value, pdf_w),
+                // Emission can't be rejected, so a degenerate sample just carries no energy
+                None => (profile.frame.normal(), vec3s(0.0), 1.0),
+            },
+            None => {
+                let (direction, pdf_w) = sample_uniform_sphere_w(dir_rnd);
+                (direction, self.intensity, pdf_w)
+            },
+        };
+
+        Emission {
+            energy: energy,
+            position: self.position,
+            direction: direction,
+            emission_pdf_w: pdf_w,
+            direct_pdf_a: 1.0,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    _ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        None
+    }
+
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn power(&self, _scene_sphere: &SceneSphere) -> f32 {
+        let solid_angle = match self.profile {
+            Some(ref profile) => profile.integral(),
+            None => 4.0 * PI,
+        };
+        luminance(self.intensity) * solid_angle
+    }
+
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        Some(point_bounds(self.position, self.power(scene_sphere)))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Spot light
+
+/// Point light emitting into a cone, at full intensity inside the inner angle and falling off
+/// smoothly to zero at the outer angle.
+pub struct SpotLight {
+    position: Vec3f,
+    frame: Frame,
+    pub intensity: Vec3f,
+    cos_inner: f32,
+    cos_outer: f32,
+    /// `1 - cos` of the angles, computed separately as the cosines of tiny angles round to 1.
+    one_minus_cos_inner: f32,
+    one_minus_cos_outer: f32,
+}
+
+/// Smallest outer angle of a spot light cone, in degrees.
+pub const MIN_SPOT_ANGLE: f32 = 1e-3;
+
+/// `1 - cos(angle)` for an angle in radians, without the cancellation of subtracting the
+/// cosine.
+fn one_minus_cos(angle: f32) -> f32 {
+    let s = (angle * 0.5).sin();
+    2.0 * s * s
 }
 
+impl SpotLight {
+    /// Angles are measured from `direction`, in degrees.
+    pub fn new(position: Vec3f, direction: Vec3f, inner_angle: f32,
+               outer_angle: f32) -> SpotLight {
+        let outer_angle = outer_angle.max(MIN_SPOT_ANGLE).min(180.0) * PI / 180.0;
+        let inner_angle = inner_angle.max(0.0) * PI / 180.0;
+        let inner_angle = inner_angle.min(outer_angle);
+
+        SpotLight {
+            position: position,
+            frame: Frame::from_z(direction),
+            intensity: vec3s(0.0),
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+            one_minus_cos_inner: one_minus_cos(inner_angle),
+            one_minus_cos_outer: one_minus_cos(outer_angle),
+        }
+    }
+
+    /// Pdf of emitting uniformly into the cone, in solid angle.
+    fn cone_pdf_w(&self) -> f32 {
+        0.5 * INV_PI / self.one_minus_cos_outer
+    }
+
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta >= self.cos_inner {
+            1.0
+        } else if cos_theta <= self.cos_outer {
+            0.0
+        } else {
+            let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+impl AbstractLight for SpotLight {
+    fn illuminate(&self, _scene_sphere: &SceneSphere, receiving_position: Vec3f,
+                  _rnd: Vec2f) -> Option<Illumination> {
+        let to_light = self.position - receiving_position;
+        let dist_sqr = to_light.length_sqr();
+        let distance = dist_sqr.sqrt();
+        let dir_to_light = to_light / vec3s(distance);
+
+        let falloff = self.falloff(self.frame.normal().dot(-dir_to_light));
+        if falloff == 0.0 {
+            return None;
+        }
+
+        Some(Illumination {
+            radiance: self.intensity * vec3s(falloff),
+            dir_to_light: dir_to_light,
+            distance: distance,
+            direct_pdf_w: dist_sqr,
+            emission_pdf_w: self.cone_pdf_w(),
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, _scene_sphere: &SceneSphere, dir_rnd: Vec2f, _pos_rnd: Vec2f) -> Emission {
+        // The returned pdf loses precision for tiny cones, unlike the direction
+        let (local_dir_out, _) = sample_uniform_cone_w(dir_rnd, self.cos_outer);
+
+        Emission {
+            energy: self.intensity * vec3s(self.falloff(local_dir_out.z)),
+            position: self.position,
+            direction: self.frame.to_world(local_dir_out),
+            emission_pdf_w: self.cone_pdf_w(),
+            direct_pdf_a: 1.0,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, _scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    _ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        None
+    }
+
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn power(&self, _scene_sphere: &SceneSphere) -> f32 {
+        // Treats the falloff region as half covered
+        luminance(self.intensity) * PI * (self.one_minus_cos_inner + self.one_minus_cos_outer)
+    }
+
+    fn bounds(&self, scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        let theta_e = self.cos_outer.acos() - self.cos_inner.acos();
+        Some(LightBounds {
+            w: self.frame.normal(),
+            cos_theta_o: self.cos_inner,
+            cos_theta_e: theta_e.cos(),
+            ..point_bounds(self.position, self.power(scene_sphere))
+        })
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Background light
+
 pub struct BackgroundLight {
     background_color: Vec3f,
     pub scale: f32,
@@ -92,5 +960,310 @@ impl BackgroundLight {
 }
 
 impl AbstractLight for BackgroundLight {
-    // TODO
+    fn illuminate(&self, scene_sphere: &SceneSphere, _receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let (direction, direct_pdf_w) = sample_uniform_sphere_w(rnd);
+
+        Some(Illumination {
+            radiance: self.background_color * vec3s(self.scale),
+            dir_to_light: direction,
+            distance: 1e36,
+            direct_pdf_w: direct_pdf_w,
+            emission_pdf_w: direct_pdf_w * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        let (direction, direct_pdf) = sample_uniform_sphere_w(dir_rnd);
+        let direction = -direction;
+
+        Emission {
+            energy: self.background_color * vec3s(self.scale),
+            position: sample_scene_disc(scene_sphere, direction, pos_rnd),
+            direction: direction,
+            emission_pdf_w: direct_pdf * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            // For background we lie about the pdf being in area measure
+            direct_pdf_a: direct_pdf,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    _ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        let direct_pdf = uniform_sphere_pdf_w();
+        let position_pdf = concentric_disc_pdf_a() * scene_sphere.inv_scene_radius_sqr;
+
+        Some(Radiance {
+            radiance: self.background_color * vec3s(self.scale),
+            direct_pdf_a: direct_pdf,
+            emission_pdf_w: direct_pdf * position_pdf,
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        false
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, scene_sphere: &SceneSphere) -> f32 {
+        luminance(self.background_color) * self.scale * 4.0 * PI *
+            PI * scene_sphere.scene_radius * scene_sphere.scene_radius
+    }
+
+    fn bounds(&self, _scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        None
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Environment light
+
+/// Image-based background light from an equirectangular map, with the top row towards +z.
+pub struct EnvironmentLight {
+    map: SphericalMap,
+    pub scale: f32,
+}
+
+impl EnvironmentLight {
+    /// `rotation` turns the map around the z axis, in degrees.
+    pub fn new(image: Framebuffer, rotation: f32) -> EnvironmentLight {
+        let (sin_rot, cos_rot) = (rotation * PI / 180.0).sin_cos();
+        let frame = Frame::new(vec3(cos_rot, sin_rot, 0.0), vec3(-sin_rot, cos_rot, 0.0),
+                               vec3(0.0, 0.0, 1.0));
+
+        EnvironmentLight {
+            map: SphericalMap::new(image, frame),
+            scale: 1.0,
+        }
+    }
+}
+
+impl AbstractLight for EnvironmentLight {
+    fn illuminate(&self, scene_sphere: &SceneSphere, _receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let (direction, value, direct_pdf_w) = match self.map.sample(rnd) {
+            Some(sample) => sample,
+            None => return None,
+        };
+
+        Some(Illumination {
+            radiance: value * vec3s(self.scale),
+            dir_to_light: direction,
+            distance: 1e36,
+            direct_pdf_w: direct_pdf_w,
+            emission_pdf_w: direct_pdf_w * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        // Emission can't be rejected, so a degenerate sample at a pole just carries no energy
+        let (direction, energy, direct_pdf) = match self.map.sample(dir_rnd) {
+            Some((direction, value, pdf)) => (-direction, value * vec3s(self.scale), pdf),
+            None => (vec3(0.0, 0.0, -1.0), vec3s(0.0), 1.0),
+        };
+
+        Emission {
+            energy: energy,
+            position: sample_scene_disc(scene_sphere, direction, pos_rnd),
+            direction: direction,
+            emission_pdf_w: direct_pdf * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            // Like the background light, this is really with respect to solid angle
+            direct_pdf_a: direct_pdf,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        let (value, direct_pdf) = self.map.evaluate(ray_direction);
+        let position_pdf = concentric_disc_pdf_a() * scene_sphere.inv_scene_radius_sqr;
+
+        Some(Radiance {
+            radiance: value * vec3s(self.scale),
+            direct_pdf_a: direct_pdf,
+            emission_pdf_w: direct_pdf * position_pdf,
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        false
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, scene_sphere: &SceneSphere) -> f32 {
+        self.map.integral() * self.scale *
+            PI * scene_sphere.scene_radius * scene_sphere.scene_radius
+    }
+
+    fn bounds(&self, _scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        None
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Sun and sky light
+
+/// Background light from the Preetham daylight model plus a finite sun disk. The sky is baked
+/// into a map for importance sampling, while the sun is sampled over the cone it subtends.
+pub struct SunSkyLight {
+    sky: SphericalMap,
+    sun_frame: Frame,
+    sun_radiance: Vec3f,
+    /// Cosine of the angular radius of the sun.
+    cos_sun: f32,
+    /// Probability of sampling the sun rather than the sky.
+    sun_prob: f32,
+    pub scale: f32,
+}
+
+impl SunSkyLight {
+    /// `sun_direction` points towards the sun, with +z up. `turbidity` ranges from about 2 for
+    /// a clear sky to 10 for a hazy one.
+    pub fn new(sun_direction: Vec3f, turbidity: f32) -> SunSkyLight {
+        let model = PreethamSky::new(sun_direction, turbidity);
+
+        let (res_x, res_y) = (256u, 128u);
+        let mut image = Framebuffer::new();
+        image.setup(vec2(res_x as f32, res_y as f32));
+        for y in range(0, res_y) {
+            let (sin_theta, cos_theta) = ((y as f32 + 0.5) / res_y as f32 * PI).sin_cos();
+            for x in range(0, res_x) {
+                let (sin_phi, cos_phi) = ((x as f32 + 0.5) / res_x as f32 * 2.0 * PI).sin_cos();
+                let direction = vec3(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta);
+                image.set_pixel(x + y * res_x, model.radiance(direction));
+            }
+        }
+        let sky = SphericalMap::new(image, Frame::identity());
+
+        let cos_sun = (SUN_ANGULAR_RADIUS * PI / 180.0).cos();
+        let sun_radiance = model.sun_radiance();
+        let sun_power = luminance(sun_radiance) * 2.0 * PI * (1.0 - cos_sun);
+        let total_power = sun_power + sky.integral();
+
+        SunSkyLight {
+            sky: sky,
+            sun_frame: Frame::from_z(model.sun_direction()),
+            sun_radiance: sun_radiance,
+            cos_sun: cos_sun,
+            sun_prob: if total_power > 0.0 { sun_power / total_power } else { 0.0 },
+            scale: 1.0,
+        }
+    }
+
+    /// Returns the radiance towards `direction` and the pdf of sampling it.
+    fn evaluate(&self, direction: Vec3f) -> (Vec3f, f32) {
+        let (sky_radiance, sky_pdf) = self.sky.evaluate(direction);
+        let mut radiance = sky_radiance;
+        let mut pdf = (1.0 - self.sun_prob) * sky_pdf;
+
+        if self.sun_frame.normal().dot(direction) >= self.cos_sun {
+            radiance = radiance + self.sun_radiance;
+            pdf += self.sun_prob * uniform_cone_pdf_w(self.cos_sun);
+        }
+
+        (radiance * vec3s(self.scale), pdf)
+    }
+
+    /// Samples a direction towards the sky or sun, returning it with its radiance and pdf.
+    fn sample(&self, rnd: Vec2f) -> Option<(Vec3f, Vec3f, f32)> {
+        let direction = if rnd.x < self.sun_prob {
+            let rnd = vec2((rnd.x / self.sun_prob).min(0.99999994), rnd.y);
+            let (local_dir, _) = sample_uniform_cone_w(rnd, self.cos_sun);
+            self.sun_frame.to_world(local_dir)
+        } else {
+            let u = ((rnd.x - self.sun_prob) / (1.0 - self.sun_prob)).min(0.99999994);
+            match self.sky.sample(vec2(u, rnd.y)) {
+                Some((direction, _, _)) => direction,
+                None => return None,
+            }
+        };
+
+        // Both strategies could have produced the direction
+        let (radiance, pdf) = self.evaluate(direction);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some((direction, radiance, pdf))
+    }
+}
+
+impl AbstractLight for SunSkyLight {
+    fn illuminate(&self, scene_sphere: &SceneSphere, _receiving_position: Vec3f,
+                  rnd: Vec2f) -> Option<Illumination> {
+        let (direction, radiance, direct_pdf_w) = match self.sample(rnd) {
+            Some(sample) => sample,
+            None => return None,
+        };
+
+        Some(Illumination {
+            radiance: radiance,
+            dir_to_light: direction,
+            distance: 1e36,
+            direct_pdf_w: direct_pdf_w,
+            emission_pdf_w: direct_pdf_w * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            cos_at_light: 1.0,
+        })
+    }
+
+    fn emit(&self, scene_sphere: &SceneSphere, dir_rnd: Vec2f, pos_rnd: Vec2f) -> Emission {
+        // Emission can't be rejected, so a failed sample just carries no energy
+        let (direction, energy, direct_pdf) = match self.sample(dir_rnd) {
+            Some((direction, radiance, pdf)) => (-direction, radiance, pdf),
+            None => (vec3(0.0, 0.0, -1.0), vec3s(0.0), 1.0),
+        };
+
+        Emission {
+            energy: energy,
+            position: sample_scene_disc(scene_sphere, direction, pos_rnd),
+            direction: direction,
+            emission_pdf_w: direct_pdf * concentric_disc_pdf_a() *
+                            scene_sphere.inv_scene_radius_sqr,
+            // Like the background light, this is really with respect to solid angle
+            direct_pdf_a: direct_pdf,
+            cos_theta_light: 1.0,
+        }
+    }
+
+    fn get_radiance(&self, scene_sphere: &SceneSphere, _ray_origin: Vec3f,
+                    ray_direction: Vec3f, _hit_point: Vec3f) -> Option<Radiance> {
+        let (radiance, direct_pdf) = self.evaluate(ray_direction);
+        let position_pdf = concentric_disc_pdf_a() * scene_sphere.inv_scene_radius_sqr;
+
+        Some(Radiance {
+            radiance: radiance,
+            direct_pdf_a: direct_pdf,
+            emission_pdf_w: direct_pdf * position_pdf,
+        })
+    }
+
+    fn is_finite(&self) -> bool {
+        false
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn power(&self, scene_sphere: &SceneSphere) -> f32 {
+        let sun_power = luminance(self.sun_radiance) * 2.0 * PI * (1.0 - self.cos_sun);
+        (self.sky.integral() + sun_power) * self.scale *
+            PI * scene_sphere.scene_radius * scene_sphere.scene_radius
+    }
+
+    fn bounds(&self, _scene_sphere: &SceneSphere) -> Option<LightBounds> {
+        None
+    }
 }
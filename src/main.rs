@@ -2,38 +2,132 @@
 #![feature(slicing_syntax)]
 #![allow(dead_code)]
 
+extern crate flate;
 extern crate getopts;
 extern crate time;
 extern crate rayon;
 
 use config::{Config, RunLimit};
+use framebuffer::Framebuffer;
+use renderer::AbstractRenderer;
 use std::cmp::max;
+use std::num::Float;
 use std::io::stdio;
 use std::os::set_exit_status;
 use std::path::Path;
 use std::iter::range_step;
 use std::sync::atomic::{Ordering, AtomicUint};
 
+mod ambientocclusion;
+mod aov;
+mod bsdf;
+mod bvh;
 mod camera;
 mod config;
+mod convergence;
+mod distribution;
 mod eyelight;
+mod film;
+mod filter;
 mod frame;
 mod framebuffer;
 mod geometry;
+mod ies;
+mod instance;
+mod lightbvh;
 mod lights;
 mod materials;
 mod math;
+mod mesh;
 mod ray;
 mod renderer;
 mod rng;
 mod scene;
+mod sky;
 mod utils;
 
+type RendererVec<'a> = Vec<Box<AbstractRenderer<'a> + Sync + 'a>>;
+
+/// Runs iterations `[first_iter, last_iter)` on all renderers, stopping early if `deadline` is
+/// reached. Returns the index of the next iteration that would've been run.
+fn run_iterations(renderers: &mut RendererVec, first_iter: uint, last_iter: uint,
+                  deadline: Option<f64>) -> uint {
+    match deadline {
+        Some(deadline) => {
+            let mut join = rayon::Section::new();
+
+            let iter = AtomicUint::new(first_iter);
+
+            for renderer in renderers.iter_mut() {
+                let renderer = renderer;
+                join.fork(&mut || {
+                    while time::precise_time_s() < deadline {
+                        let i = iter.fetch_add(1, Ordering::Release);
+                        if i >= last_iter {
+                            break;
+                        }
+                        renderer.run_iteration(i as u32);
+                    }
+                });
+            }
+
+            join.sync();
+            std::cmp::min(iter.load(Ordering::Acquire), last_iter)
+        },
+        None => {
+            let mut join = rayon::Section::new();
+
+            // Iteration i is always run by renderer i % num_renderers, regardless of batching
+            let num_renderers = renderers.len();
+            for (thread_id, renderer) in renderers.iter_mut().enumerate() {
+                let first = first_iter + (thread_id + num_renderers - first_iter % num_renderers) % num_renderers;
+                join.fork(&mut || {
+                    for i in range_step(first, last_iter, num_renderers) {
+                        renderer.run_iteration(i as u32);
+                    }
+                });
+            }
+
+            join.sync();
+            last_iter
+        },
+    }
+}
+
+fn merge_framebuffers(renderers: &RendererVec) -> Framebuffer {
+    let mut used_renderers = 0u;
+
+    let mut framebuffer = None;
+
+    for renderer in renderers.iter() {
+        if !renderer.base().was_used() {
+            continue;
+        }
+
+        let renderer_fb = renderer.base().get_framebuffer();
+
+        match framebuffer {
+            None => framebuffer = Some(renderer_fb),
+            Some(ref mut framebuffer) => framebuffer.add(&renderer_fb),
+        };
+
+        used_renderers += 1;
+    }
+
+    match framebuffer {
+        Some(mut framebuffer) => {
+            framebuffer.scale(1.0 / used_renderers as f32);
+            framebuffer
+        },
+        None => unreachable!(),
+    }
+}
+
 fn render(config: &mut Config) -> (f64, uint) {
-    use renderer::AbstractRenderer;
+    let mut convergence = config.convergence.take();
 
     let (framebuffer, result) = {
-        let mut renderers = Vec::with_capacity(config.num_threads as uint);
+        let mut renderers: RendererVec = Vec::with_capacity(config.num_threads as uint);
 
         for i in range(0, config.num_threads) {
             let mut renderer = config::create_renderer(config, config.base_seed + i as u32);
@@ -41,76 +135,66 @@ fn render(config: &mut Config) -> (f64, uint) {
                 let render_base = renderer.base_mut();
                 render_base.max_path_length = config.max_path_length;
                 render_base.min_path_length = config.min_path_length;
+                render_base.set_filter(config.filter);
+                match config.crop {
+                    Some(crop) => render_base.set_crop(crop),
+                    None => (),
+                }
             }
             renderers.push(renderer);
         }
 
-        let start_time = time::precise_time_s();
+        // Rendering is split into batches so the convergence curve can be sampled in between.
+        // Time spent computing errors is not counted as render time.
+        let mut render_time = 0.0;
+        let mut iter = 0u;
 
-        let iter = match config.run_limit {
+        match config.run_limit {
             RunLimit::Time(max_time) => {
-                let mut join = rayon::Section::new();
+                let interval = config.convergence_interval.unwrap_or(max_time);
 
-                let iter = AtomicUint::new(0);
+                while render_time < max_time {
+                    let batch_time = interval.min(max_time - render_time);
 
-                for renderer in renderers.iter_mut() {
-                    let renderer = renderer;
-                    join.fork(&mut || {
-                        while time::precise_time_s() < start_time + max_time {
-                            let i = iter.fetch_add(1, Ordering::Release);
-                            renderer.run_iteration(i as u32);
-                        }
-                    });
-                }
+                    let start_time = time::precise_time_s();
+                    iter = run_iterations(&mut renderers, iter, std::uint::MAX,
+                                          Some(start_time + batch_time));
+                    render_time += time::precise_time_s() - start_time;
 
-                join.sync();
-                iter.load(Ordering::Acquire)
+                    match convergence {
+                        Some(ref mut log) if iter > 0 =>
+                            log.record(iter, render_time, &merge_framebuffers(&renderers)),
+                        _ => (),
+                    }
+                }
             },
             RunLimit::Iterations(iterations) => {
-                let mut join = rayon::Section::new();
-
-                let num_renderers = renderers.len();
-                for (thread_id, renderer) in renderers.iter_mut().enumerate() {
-                    join.fork(&mut || {
-                        for i in range_step(thread_id, iterations, num_renderers) {
-                            renderer.run_iteration(i as u32);
-                        }
-                    });
+                let interval = match config.convergence_interval {
+                    Some(interval) => max(1, interval as uint),
+                    None => iterations,
+                };
+
+                while iter < iterations {
+                    let batch_end = std::cmp::min(iter + interval, iterations);
+
+                    let start_time = time::precise_time_s();
+                    iter = run_iterations(&mut renderers, iter, batch_end, None);
+                    render_time += time::precise_time_s() - start_time;
+
+                    match convergence {
+                        Some(ref mut log) =>
+                            log.record(iter, render_time, &merge_framebuffers(&renderers)),
+                        None => (),
+                    }
                 }
-
-                iterations
             },
-        };
-
-        let end_time = time::precise_time_s();
-
-        let mut used_renderers = 0u;
-
-        let mut framebuffer = None;
-
-        for renderer in renderers.iter_mut() {
-            if !renderer.base().was_used() {
-                continue;
-            }
-
-            let renderer_fb = renderer.base().get_framebuffer();
-
-            match framebuffer {
-                None => framebuffer = Some(renderer_fb),
-                Some(ref mut framebuffer) => framebuffer.add(&renderer_fb),
-            };
-
-            used_renderers += 1;
         }
 
-        match framebuffer {
-            Some(ref mut framebuffer) => framebuffer.scale(1.0 / used_renderers as f32),
-            None => unreachable!(),
-        }
-        (framebuffer, (end_time - start_time, iter))
+        (merge_framebuffers(&renderers), (render_time, iter))
     };
 
-    config.framebuffer = framebuffer;
+    config.framebuffer = Some(framebuffer);
+    config.convergence = convergence;
 
     result
 }
@@ -157,12 +241,23 @@ fn main() {
     let (time, iters) = render(&mut config);
     println!("done {} iterations in {:.2} s", iters, time);
 
+    match config.convergence {
+        Some(ref log) => log.save_csv(&Path::new(config.convergence_csv[])).unwrap(),
+        None => (),
+    }
+
+    if config.crop_embed {
+        let resolution = config.scene.as_ref().unwrap().camera.resolution();
+        config.framebuffer = Some(config.framebuffer.as_ref().unwrap().embedded(resolution));
+    }
+
     let extension = config.output_name[].rsplitn(1, '.').next();
     let path = Path::new(config.output_name[]);
 
     match extension {
         Some("bmp") => config.framebuffer.unwrap().save_bmp(&path, 2.2).unwrap(),
         Some("hdr") => config.framebuffer.unwrap().save_hdr(&path).unwrap(),
+        Some("pfm") => config.framebuffer.unwrap().save_pfm(&path).unwrap(),
         Some(other_ext) => {
             println!("Used unknown extension {}", other_ext);
             set_exit_status(1);
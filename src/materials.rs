@@ -1,4 +1,5 @@
 use math::{Vec3f, vec3s};
+use lights::EmissionMode;
 
 pub struct Material {
     pub diffuse_reflectance: Vec3f,
@@ -6,6 +7,10 @@ pub struct Material {
     pub phong_exponent: f32,
     pub mirror_reflectance: Vec3f,
     pub ior: f32,
+    /// Radiance emitted from surfaces using the material, which turns them into area lights.
+    pub emission: Vec3f,
+    /// Which sides of the surfaces emit, relative to their winding.
+    pub emission_mode: EmissionMode,
 }
 
 impl Material {
@@ -16,6 +21,12 @@ impl Material {
             phong_exponent: 1.0,
             mirror_reflectance: vec3s(0.0),
             ior: -1.0,
+            emission: vec3s(0.0),
+            emission_mode: EmissionMode::Front,
         }
     }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.x > 0.0 || self.emission.y > 0.0 || self.emission.z > 0.0
+    }
 }
@@ -28,6 +28,41 @@ pub fn sqr<T: Mul<T, T>>(a: T) -> T {
     a * a
 }
 
+/// Half the distance between 1.0 and the next representable f32.
+pub const MACHINE_EPSILON: f32 = 5.96046448e-8;
+
+/// Bound on the relative error accumulated by `n` floating point operations.
+#[inline]
+pub fn gamma(n: uint) -> f32 {
+    let n_eps = n as f32 * MACHINE_EPSILON;
+    n_eps / (1.0 - n_eps)
+}
+
+/// Smallest representable f32 greater than `v`.
+pub fn next_float_up(v: f32) -> f32 {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+    // Skip -0.0, which would otherwise step to the smallest negative denormal
+    let v = if v == 0.0 { 0.0 } else { v };
+
+    let bits: u32 = unsafe { ::std::mem::transmute(v) };
+    let bits = if v >= 0.0 { bits + 1 } else { bits - 1 };
+    unsafe { ::std::mem::transmute(bits) }
+}
+
+/// Largest representable f32 smaller than `v`.
+pub fn next_float_down(v: f32) -> f32 {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+    let v = if v == 0.0 { -0.0 } else { v };
+
+    let bits: u32 = unsafe { ::std::mem::transmute(v) };
+    let bits = if v > 0.0 { bits - 1 } else { bits + 1 };
+    unsafe { ::std::mem::transmute(bits) }
+}
+
 macro_rules! impl_Vector_op(
     ($Trait:ident for $Self:ident { $($field:ident),+ }, $func:ident) => (
         impl<T: Num> $Trait<$Self<T>, $Self<T>> for $Self<T> {
@@ -135,16 +170,42 @@ impl<T: Num + Copy> Vector3<T> {
     }
 
     #[inline]
-    fn get_xy(&self) -> Vector2<T> {
+    pub fn get_xy(&self) -> Vector2<T> {
         Vector2 { x: self.x, y: self.y }
     }
 
     #[inline]
-    fn max(&self) -> T {
+    pub fn max(&self) -> T {
         let max_xy = if self.x < self.y { self.y } else { self.x };
         if max_xy < self.z { self.z } else { max_xy }
     }
 
+    #[inline]
+    pub fn min(&self) -> T {
+        let min_xy = if self.y < self.x { self.y } else { self.x };
+        if self.z < min_xy { self.z } else { min_xy }
+    }
+
+    /// Component-wise minimum.
+    #[inline]
+    pub fn min_with(&self, o: Vector3<T>) -> Vector3<T> {
+        Vector3 {
+            x: if o.x < self.x { o.x } else { self.x },
+            y: if o.y < self.y { o.y } else { self.y },
+            z: if o.z < self.z { o.z } else { self.z },
+        }
+    }
+
+    /// Component-wise maximum.
+    #[inline]
+    pub fn max_with(&self, o: Vector3<T>) -> Vector3<T> {
+        Vector3 {
+            x: if self.x < o.x { o.x } else { self.x },
+            y: if self.y < o.y { o.y } else { self.y },
+            z: if self.z < o.z { o.z } else { self.z },
+        }
+    }
+
     #[inline]
     pub fn dot(&self, o: Vector3<T>) -> T {
         (self.x * o.x) + (self.y * o.y) + (self.z * o.z)
@@ -193,6 +254,7 @@ impl<T: Show> Show for Vector3<T> {
     }
 }
 
+#[deriving(Copy, Clone)]
 pub struct Mat4f {
     // stored row major: data[row][column]
     data: [[f32, ..4], ..4]
@@ -217,7 +279,8 @@ impl Mat4f {
         self.data[row] = [xyz.x, xyz.y, xyz.z, w];
     }
 
-    fn transform_vector(&self, vec: &Vec3f) -> Vec3f {
+    /// Transforms a direction, ignoring translation and projection.
+    pub fn transform_vector(&self, vec: &Vec3f) -> Vec3f {
         let mut res : Vec3f = vec3s(0.0);
         for r in range(0, 3) {
             for c in range(0, 3) {
@@ -227,6 +290,18 @@ impl Mat4f {
         res
     }
 
+    /// Transforms a normal by the transpose of the upper 3x3 part. To transform the normals of
+    /// points transformed by M, call this on the inverse of M.
+    pub fn transform_normal(&self, normal: &Vec3f) -> Vec3f {
+        let mut res : Vec3f = vec3s(0.0);
+        for r in range(0, 3) {
+            for c in range(0, 3) {
+                res[r] += normal[c] * self[(c, r)]
+            }
+        }
+        res
+    }
+
     pub fn transform_point(&self, vec: &Vec3f) -> Vec3f {
         let mut w = self[(3, 3)];
         for c in range(0, 3) {
@@ -277,6 +352,34 @@ impl Mat4f {
         res
     }
 
+    /// Rotation of `angle` degrees around `axis`, counter-clockwise when looking down the axis.
+    pub fn rotate(axis: &Vec3f, angle: f32) -> Mat4f {
+        let a = axis.normalized();
+        let (sin, cos) = (angle * PI / 180.0).sin_cos();
+        let t = 1.0 - cos;
+
+        let mut res = Mat4f::identity();
+        res.set_row(0, t * a.x * a.x + cos, t * a.x * a.y - sin * a.z,
+                    t * a.x * a.z + sin * a.y, 0.0);
+        res.set_row(1, t * a.x * a.y + sin * a.z, t * a.y * a.y + cos,
+                    t * a.y * a.z - sin * a.x, 0.0);
+        res.set_row(2, t * a.x * a.z - sin * a.y, t * a.y * a.z + sin * a.x,
+                    t * a.z * a.z + cos, 0.0);
+        res
+    }
+
+    /// Element-wise linear interpolation. Points transformed by the result move linearly between
+    /// their positions under both affine transforms.
+    pub fn lerp(&self, o: &Mat4f, t: f32) -> Mat4f {
+        let mut res = Mat4f::zero();
+        for r in range(0, 4) {
+            for c in range(0, 4) {
+                res[(r, c)] = self[(r, c)] * (1.0 - t) + o[(r, c)] * t;
+            }
+        }
+        res
+    }
+
     pub fn perspective(fov: f32, near: f32, far: f32) -> Mat4f {
         // Camera points towards -z. 0 < near < far.
         // Matrix maps z range [-near, -far] to [-1, 1], after homogeneous division
@@ -440,3 +543,87 @@ impl Mul<Mat4f, Mat4f> for Mat4f {
         res
     }
 }
+
+/// Axis-aligned bounding box. The empty box has min > max, so growing it by anything yields
+/// exactly that thing.
+#[deriving(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3f,
+    pub max: Vec3f,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3f, max: Vec3f) -> Aabb {
+        Aabb { min: min, max: max }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb { min: vec3s(1e36), max: vec3s(-1e36) }
+    }
+
+    pub fn from_point(p: Vec3f) -> Aabb {
+        Aabb { min: p, max: p }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&self, p: Vec3f) -> Aabb {
+        Aabb { min: self.min.min_with(p), max: self.max.max_with(p) }
+    }
+
+    pub fn union(&self, o: &Aabb) -> Aabb {
+        Aabb { min: self.min.min_with(o.min), max: self.max.max_with(o.max) }
+    }
+
+    /// The overlap of both boxes, empty if they are disjoint.
+    pub fn intersection(&self, o: &Aabb) -> Aabb {
+        Aabb { min: self.min.max_with(o.min), max: self.max.min_with(o.max) }
+    }
+
+    pub fn extent(&self) -> Vec3f {
+        self.max - self.min
+    }
+
+    pub fn centroid(&self) -> Vec3f {
+        (self.min + self.max) * vec3s(0.5)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.extent();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test. Returns whether the box overlaps the ray in `(tmin, tmax)`. Takes the inverse
+    /// direction so it can be computed once per traversal.
+    #[inline]
+    pub fn intersect_ray(&self, org: Vec3f, inv_dir: Vec3f, tmin: f32, tmax: f32) -> bool {
+        let t0 = (self.min - org) * inv_dir;
+        let t1 = (self.max - org) * inv_dir;
+
+        let enter = t0.min_with(t1).max().max(tmin);
+        let exit = t0.max_with(t1).min().min(tmax);
+
+        enter <= exit
+    }
+
+    /// Bounds of the box after transforming all its corners.
+    pub fn transformed(&self, m: &Mat4f) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        let mut res = Aabb::empty();
+        for i in range(0u, 8) {
+            let corner = vec3(if (i & 1) != 0 { self.max.x } else { self.min.x },
+                              if (i & 2) != 0 { self.max.y } else { self.min.y },
+                              if (i & 4) != 0 { self.max.z } else { self.min.z });
+            res = res.grow(m.transform_point(&corner));
+        }
+        res
+    }
+}
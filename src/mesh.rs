@@ -0,0 +1,206 @@
+use bvh::{Bvh, BvhPrimitives};
+use geometry::{AbstractGeometry, Emitter, EmitterShape, intersect_triangle, barycentric_error,
+               is_emissive};
+use math::{Vec2f, Vec3f, Aabb, vec2, vec2s, vec3, vec3s, PI};
+use ray::{Ray, Isect};
+use std::num::{Float, FloatMath};
+
+/// Indexed triangle mesh with shared vertex attributes, intersected through an internal BVH.
+pub struct TriangleMesh {
+    positions: Vec<Vec3f>,
+    indices: Vec<[u32, ..3]>,
+    /// Per-triangle material ids.
+    pub mat_ids: Vec<int>,
+    /// Optional per-vertex normals. Empty if not present.
+    pub normals: Vec<Vec3f>,
+    /// Optional per-vertex texture coordinates. Empty if not present.
+    pub uvs: Vec<Vec2f>,
+    bvh: Bvh,
+}
+
+impl TriangleMesh {
+    pub fn new(positions: Vec<Vec3f>, indices: Vec<[u32, ..3]>, mat_ids: Vec<int>) -> TriangleMesh {
+        assert_eq!(indices.len(), mat_ids.len());
+
+        let bboxes: Vec<Aabb> = indices.iter().map(|tri| {
+            Aabb::from_point(positions[tri[0] as uint])
+                .grow(positions[tri[1] as uint])
+                .grow(positions[tri[2] as uint])
+        }).collect();
+        let bvh = Bvh::build(bboxes[]);
+
+        TriangleMesh {
+            positions: positions,
+            indices: indices,
+            mat_ids: mat_ids,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            bvh: bvh,
+        }
+    }
+
+    /// Tessellates a sphere into a mesh with smooth normals. `rings` is the number of subdivisions
+    /// from pole to pole and `segments` around the equator.
+    pub fn uv_sphere(center: Vec3f, radius: f32, rings: uint, segments: uint,
+                     mat_id: int) -> TriangleMesh {
+        let mut positions = Vec::with_capacity((rings + 1) * (segments + 1));
+        let mut normals = Vec::with_capacity((rings + 1) * (segments + 1));
+        let mut uvs = Vec::with_capacity((rings + 1) * (segments + 1));
+
+        for r in range(0, rings + 1) {
+            let v = r as f32 / rings as f32;
+            let theta = v * PI;
+            for s in range(0, segments + 1) {
+                let u = s as f32 / segments as f32;
+                let phi = u * 2.0 * PI;
+
+                let n = vec3(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                positions.push(center + n * vec3s(radius));
+                normals.push(n);
+                uvs.push(vec2(u, v));
+            }
+        }
+
+        let mut indices = Vec::with_capacity(rings * segments * 2);
+        let stride = (segments + 1) as u32;
+        for r in range(0, rings as u32) {
+            for s in range(0, segments as u32) {
+                let i0 = r * stride + s;
+                let i1 = i0 + stride;
+
+                // Skip the degenerate triangles at the poles
+                if r != 0 {
+                    indices.push([i0, i1, i0 + 1]);
+                }
+                if r != rings as u32 - 1 {
+                    indices.push([i0 + 1, i1, i1 + 1]);
+                }
+            }
+        }
+
+        let mat_ids = Vec::from_elem(indices.len(), mat_id);
+        let mut mesh = TriangleMesh::new(positions, indices, mat_ids);
+        mesh.normals = normals;
+        mesh.uvs = uvs;
+        mesh
+    }
+
+    pub fn positions(&self) -> &[Vec3f] {
+        self.positions[]
+    }
+
+    pub fn indices(&self) -> &[[u32, ..3]] {
+        self.indices[]
+    }
+
+    pub fn num_triangles(&self) -> uint {
+        self.indices.len()
+    }
+
+    pub fn triangle_vertices(&self, index: uint) -> (Vec3f, Vec3f, Vec3f) {
+        let tri = &self.indices[index];
+        (self.positions[tri[0] as uint],
+         self.positions[tri[1] as uint],
+         self.positions[tri[2] as uint])
+    }
+}
+
+impl BvhPrimitives for TriangleMesh {
+    fn intersect_primitive(&self, index: uint, ray: &Ray, result: &mut Isect) -> bool {
+        let (p0, p1, p2) = self.triangle_vertices(index);
+
+        let (distance, bary) = match intersect_triangle(p0, p1, p2, ray) {
+            Some((distance, bary)) if distance > ray.tmin && distance < result.dist =>
+                (distance, bary),
+            _ => return false,
+        };
+
+        let tri = &self.indices[index];
+        let b0 = 1.0 - bary.x - bary.y;
+        let mut geom_normal = (p1 - p0).cross(p2 - p0).normalized();
+
+        let shading_normal = if self.normals.is_empty() {
+            geom_normal
+        } else {
+            let n = self.normals[tri[0] as uint] * vec3s(b0) +
+                    self.normals[tri[1] as uint] * vec3s(bary.x) +
+                    self.normals[tri[2] as uint] * vec3s(bary.y);
+
+            // Vertex normals are more reliable than the winding order for telling the outside
+            if n.dot(geom_normal) < 0.0 {
+                geom_normal = -geom_normal;
+            }
+            n.normalized()
+        };
+
+        result.dist = distance;
+        result.mat_id = self.mat_ids[index];
+        result.position = p0 * vec3s(b0) + p1 * vec3s(bary.x) + p2 * vec3s(bary.y);
+        result.p_error = barycentric_error(p0, p1, p2, bary);
+        result.prim_id = index as int;
+        result.geom_normal = geom_normal;
+        result.shading_normal = shading_normal;
+
+        if self.uvs.is_empty() {
+            result.uv = bary;
+            result.dpdu = p1 - p0;
+        } else {
+            let uv0 = self.uvs[tri[0] as uint];
+            let uv1 = self.uvs[tri[1] as uint];
+            let uv2 = self.uvs[tri[2] as uint];
+            result.uv = uv0 * vec2s(b0) + uv1 * vec2s(bary.x) + uv2 * vec2s(bary.y);
+
+            // Solve for the derivative of the position along the u texture axis
+            let duv02 = uv0 - uv2;
+            let duv12 = uv1 - uv2;
+            let det = duv02.x * duv12.y - duv02.y * duv12.x;
+            result.dpdu = if det.abs() < 1e-9 {
+                p1 - p0
+            } else {
+                ((p0 - p2) * vec3s(duv12.y) - (p1 - p2) * vec3s(duv02.y)) / vec3s(det)
+            };
+        }
+
+        true
+    }
+
+    fn occluded_primitive(&self, index: uint, ray: &Ray) -> bool {
+        let (p0, p1, p2) = self.triangle_vertices(index);
+
+        match intersect_triangle(p0, p1, p2, ray) {
+            Some((distance, _)) => distance > ray.tmin && distance < ray.tmax,
+            None => false,
+        }
+    }
+}
+
+impl AbstractGeometry for TriangleMesh {
+    fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        self.bvh.intersect(self, ray, result)
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.bvh.occluded(self, ray)
+    }
+
+    fn bbox(&self) -> Aabb {
+        self.bvh.bbox()
+    }
+
+    fn emitters(&self, emissive: &[bool]) -> Vec<Emitter> {
+        let mut emitters = Vec::new();
+        for (index, &mat_id) in self.mat_ids.iter().enumerate() {
+            if !is_emissive(emissive, mat_id) {
+                continue;
+            }
+            let (p0, p1, p2) = self.triangle_vertices(index);
+            emitters.push(Emitter {
+                mesh_id: 0,
+                prim_id: index as int,
+                mat_id: mat_id,
+                shape: EmitterShape::Triangle(p0, p1, p2),
+            });
+        }
+        emitters
+    }
+}
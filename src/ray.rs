@@ -1,16 +1,99 @@
-use math::{Vec3f, vec3s};
+use math::{Vec2f, Vec3f, vec2s, vec3, vec3s, next_float_up, next_float_down};
+use frame::Frame;
+use std::num::Float;
+
+/// Shortens shadow rays slightly so they don't hit the surface they're aimed at.
+const SHADOW_EPSILON: f32 = 1e-4;
 
 pub struct Ray {
     pub org: Vec3f,
     pub dir: Vec3f,
     pub tmin: f32,
+    pub tmax: f32,
+    /// Time in [0, 1] over the shutter interval, used to place moving camera and geometry.
+    pub time: f32,
+}
+
+impl Ray {
+    /// Spawns a ray leaving the surface at `isect`, with its origin offset so it can't
+    /// intersect the same surface again.
+    pub fn spawn(isect: &Isect, dir: Vec3f) -> Ray {
+        Ray {
+            org: offset_ray_origin(isect.position, isect.p_error, isect.geom_normal, dir),
+            dir: dir,
+            tmin: 0.0,
+            tmax: 1e36,
+            time: isect.time,
+        }
+    }
+
+    /// Spawns a ray leaving the surface at `isect` towards `target`, stopping just short of it.
+    pub fn spawn_to(isect: &Isect, target: Vec3f) -> Ray {
+        let org = offset_ray_origin(isect.position, isect.p_error, isect.geom_normal,
+                                    target - isect.position);
+        let to_target = target - org;
+        let dist = to_target.length();
+
+        Ray {
+            org: org,
+            dir: to_target / vec3s(dist),
+            tmin: 0.0,
+            tmax: dist * (1.0 - SHADOW_EPSILON),
+            time: isect.time,
+        }
+    }
 }
 
+/// Offsets `p`, which has an absolute error of up to `p_error`, along the geometric normal `n`
+/// just enough that it's guaranteed to be on the side of the surface `w` points to.
+pub fn offset_ray_origin(p: Vec3f, p_error: Vec3f, n: Vec3f, w: Vec3f) -> Vec3f {
+    let d = vec3(n.x.abs(), n.y.abs(), n.z.abs()).dot(p_error);
+    let mut offset = n * vec3s(d);
+    if w.dot(n) < 0.0 {
+        offset = -offset;
+    }
+
+    let mut po = p + offset;
+
+    // Round away from p, so the offset isn't lost to rounding
+    for i in range(0, 3) {
+        if offset[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+
+    po
+}
+
+#[deriving(Copy, Clone)]
 pub struct Isect {
     pub dist: f32,
     pub mat_id: int,
     pub light_id: int,
-    pub normal: Vec3f,
+
+    pub position: Vec3f,
+    /// Conservative bound on the absolute error of each component of `position`.
+    pub p_error: Vec3f,
+    /// Texture coordinates if the geometry has them. Otherwise barycentric coordinates of the 2nd
+    /// and 3rd vertices for triangles, or the surface parametrization for other shapes.
+    pub uv: Vec2f,
+    /// Index of the hit geometry in the scene.
+    pub mesh_id: int,
+    /// Index of the hit primitive inside its geometry, or 0 if it's a single primitive. Geometry
+    /// lists fold the index of their child into it, so it only serves as a key together with
+    /// `mesh_id`.
+    pub prim_id: int,
+
+    pub geom_normal: Vec3f,
+    pub shading_normal: Vec3f,
+    /// Derivative of `position` with respect to `uv.x`. Used to orient `frame`.
+    pub dpdu: Vec3f,
+    /// Tangent frame around the shading normal. Only filled in by `Scene::intersect`.
+    pub frame: Frame,
+    /// Time of the ray that found the intersection, inherited by rays spawned from it.
+    pub time: f32,
 }
 
 impl Isect {
@@ -19,7 +102,16 @@ impl Isect {
             dist: 1e36,
             mat_id: -1,
             light_id: -1,
-            normal: vec3s(0.0),
+            position: vec3s(0.0),
+            p_error: vec3s(0.0),
+            uv: vec2s(0.0),
+            mesh_id: -1,
+            prim_id: -1,
+            geom_normal: vec3s(0.0),
+            shading_normal: vec3s(0.0),
+            dpdu: vec3s(0.0),
+            frame: Frame::identity(),
+            time: 0.0,
         }
     }
 }
@@ -1,5 +1,8 @@
-use framebuffer::Framebuffer;
+use framebuffer::{Framebuffer, CropWindow};
+use film::Film;
+use filter::Filter;
 use scene::Scene;
+use std::num::Float;
 
 pub trait AbstractRenderer<'a> {
     fn base<'b>(&'b self) -> &'b RendererBase<'a>;
@@ -13,32 +16,51 @@ pub struct RendererBase<'a> {
 
     // originally protected
     pub iterations: u32,
-    pub framebuffer: Framebuffer,
+    pub film: Film,
     pub scene: &'a Scene,
+    /// Pixels the film stores. Defaults to the whole image.
+    pub crop: CropWindow,
+    /// Pixels camera-driven renderers trace: the crop grown by the filter support, so pixels on
+    /// the crop edge receive the same samples they would in a full render.
+    pub trace_window: CropWindow,
 }
 
 impl<'a> RendererBase<'a> {
     pub fn new(scene: &Scene) -> RendererBase {
-        let mut framebuffer = Framebuffer::new();
-        framebuffer.setup(scene.camera.resolution);
+        let crop = CropWindow::full(scene.camera.resolution());
 
         RendererBase {
             max_path_length: 0,
             min_path_length: 2,
             iterations: 0,
-            framebuffer: framebuffer,
+            film: Film::new(crop, Filter::Box),
             scene: scene,
+            crop: crop,
+            trace_window: crop,
         }
     }
 
-    pub fn get_framebuffer(&self) -> Framebuffer {
-        let mut framebuffer = self.framebuffer.clone();
+    /// Restricts rendering to `crop`. Light-driven splats outside it are dropped.
+    pub fn set_crop(&mut self, crop: CropWindow) {
+        self.crop = crop;
+        self.film = Film::new(crop, self.film.filter());
+        self.update_trace_window();
+    }
 
-        if self.iterations > 0 {
-            framebuffer.scale(1.0 / self.iterations as f32);
-        }
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.film = Film::new(self.crop, filter);
+        self.update_trace_window();
+    }
 
-        framebuffer
+    fn update_trace_window(&mut self) {
+        // A box filter of radius 0.5 reaches no neighbours; wider filters reach one more pixel
+        // per started pixel of radius
+        let border = (self.film.filter().radius() - 0.5).ceil().max(0.0) as i32;
+        self.trace_window = self.crop.grown(border, self.scene.camera.resolution());
+    }
+
+    pub fn get_framebuffer(&self) -> Framebuffer {
+        self.film.resolve(self.iterations)
     }
 
     pub fn was_used(&self) -> bool {
@@ -55,6 +55,10 @@ impl SeedableRng<[u64, ..2]> for XorShift128Plus {
 }
 
 pub trait MathRng : StdRng {
+    fn get_f32(&mut self) -> f32 {
+        self.next_f32()
+    }
+
     fn get_vec2f(&mut self) -> Vec2f {
         vec2(self.next_f32(), self.next_f32())
     }
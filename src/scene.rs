@@ -1,10 +1,17 @@
-use std::collections::VecMap;
+use std::collections::HashMap;
 use std::num::Float;
-use math::{sqr, Vec2i, vec2, vec3, vec3s, INV_PI};
-use camera::Camera;
+use math::{sqr, Vec2i, Vec3f, vec2, vec3, vec3s, INV_PI};
+use camera::{AbstractCamera, CameraSettings, CameraView, CameraPose, Fov};
+use frame::Frame;
 use materials::Material;
-use geometry::{AbstractGeometry, GeometryList, Triangle, Sphere};
-use lights::{SceneSphere, AbstractLight, AreaLight, DirectionalLight, PointLight, BackgroundLight};
+use geometry::{AbstractGeometry, GeometryList, EmitterShape, Triangle, Sphere, Disk, Quad,
+               Cylinder};
+use lights::{SceneSphere, AbstractLight, AreaLight, SphereLight, ShapeLight, DirectionalLight,
+             PointLight, SpotLight, BackgroundLight, LightSelection, EmissionMode};
+use framebuffer::Framebuffer;
+use distribution::Distribution1D;
+use lightbvh::LightBvh;
+use std::cmp::min;
 use ray::{Ray, Isect};
 
 bitflags! {
@@ -27,14 +34,45 @@ bitflags! {
     }
 }
 
+/// Corners of the Cornell box, back face first. The front face is left open.
+pub fn cornell_box_corners() -> [Vec3f, ..8] {
+    [
+        vec3(-1.27029,  1.30455, -1.28002),
+        vec3( 1.28975,  1.30455, -1.28002),
+        vec3( 1.28975,  1.30455,  1.28002),
+        vec3(-1.27029,  1.30455,  1.28002),
+        vec3(-1.27029, -1.25549, -1.28002),
+        vec3( 1.28975, -1.25549, -1.28002),
+        vec3( 1.28975, -1.25549,  1.28002),
+        vec3(-1.27029, -1.25549,  1.28002),
+    ]
+}
+
+/// Position and intensity of the point light in `LIGHT_POINT` scenes.
+fn point_light_setup() -> (Vec3f, Vec3f) {
+    (vec3(0.0, -0.5, 1.0), vec3s(70.0 * (INV_PI * 0.25)))
+}
+
 pub struct Scene {
     geometry: GeometryList,
-    pub camera: Camera,
+    pub camera: Box<AbstractCamera + Sync + Send + 'static>,
     materials: Vec<Material>,
     lights: Vec<Box<AbstractLight + Sync + 'static>>,
-    material_to_light: VecMap<int>,
+    /// Light ids of emissive primitives, keyed by their mesh and primitive ids.
+    emitter_to_light: HashMap<(int, int), int>,
     scene_sphere: SceneSphere,
     background: Option<uint>,
+    /// The light of `LIGHT_POINT` scenes, which can be turned into a spot or profiled light.
+    point_light: Option<uint>,
+    /// The directional light of `LIGHT_SUN` scenes.
+    sun_light: Option<uint>,
+    light_selection: LightSelection,
+    /// Probabilities of picking each light, rebuilt whenever the lights or scene sphere change.
+    light_distribution: Distribution1D,
+    /// Only built for `LightSelection::Bvh`, which picks the lights in `infinite_lights`
+    /// separately.
+    light_bvh: Option<LightBvh>,
+    infinite_lights: Vec<uint>,
 
     pub scene_name: String,
     pub scene_acronym: String,
@@ -42,17 +80,209 @@ pub struct Scene {
 
 impl Scene {
     pub fn intersect(&self, ray: &Ray, result: &mut Isect) -> bool {
+        result.dist = result.dist.min(ray.tmax);
         let hit = self.geometry.intersect(ray, result);
 
         if hit {
-            let lid = self.material_to_light.get(&(result.mat_id as uint));
+            let lid = self.emitter_to_light.get(&(result.mesh_id, result.prim_id));
             result.light_id = lid.map_or(-1, |&x| x);
+            result.frame = Frame::from_z_tangent(result.shading_normal, result.dpdu);
+            result.time = ray.time;
         }
 
         hit
     }
 
-    pub fn load_cornell_box(resolution: Vec2i, mut box_mask: BoxMask) -> Scene {
+    /// Geometry of the scene, for queries that don't need the light ids `intersect` fills in.
+    pub fn geometry(&self) -> &AbstractGeometry {
+        &self.geometry
+    }
+
+    /// Tests whether anything blocks `dir` from the surface point `from` before distance `tmax`.
+    pub fn occluded(&self, from: &Isect, dir: Vec3f, tmax: f32) -> bool {
+        let ray = Ray { tmax: tmax, ..Ray::spawn(from, dir) };
+        self.geometry.occluded(&ray)
+    }
+
+    /// Tests whether the segment between the surface point `from` and `to` is blocked.
+    pub fn occluded_segment(&self, from: &Isect, to: Vec3f) -> bool {
+        self.geometry.occluded(&Ray::spawn_to(from, to))
+    }
+
+    pub fn get_material(&self, mat_id: int) -> &Material {
+        &self.materials[mat_id as uint]
+    }
+
+    pub fn scene_sphere(&self) -> &SceneSphere {
+        &self.scene_sphere
+    }
+
+    pub fn get_light_count(&self) -> uint {
+        self.lights.len()
+    }
+
+    pub fn get_light(&self, light_id: int) -> &AbstractLight {
+        &*self.lights[light_id as uint]
+    }
+
+    pub fn get_background(&self) -> Option<&AbstractLight> {
+        self.background.map(|id| &*self.lights[id])
+    }
+
+    /// Replaces the background light, or adds one if the scene had none.
+    pub fn set_background(&mut self, light: Box<AbstractLight + Sync + 'static>) {
+        match self.background {
+            Some(id) => self.lights[mut][id] = light,
+            None => {
+                self.background = Some(self.lights.len());
+                self.lights.push(light);
+            }
+        }
+        self.build_light_distribution();
+    }
+
+    /// Replaces the point light with a spot light aimed at the floor, with angles in degrees.
+    /// Scenes without a point light are left unchanged.
+    pub fn set_spot_light(&mut self, inner_angle: f32, outer_angle: f32) {
+        let (position, intensity) = point_light_setup();
+        let mut light = box SpotLight::new(position, vec3(0.0, 0.0, -1.0), inner_angle,
+                                           outer_angle);
+        light.intensity = intensity;
+        self.replace_point_light(light);
+    }
+
+    /// Modulates the point light by a profile with its nadir facing the floor, as made by
+    /// `IesProfile::to_image`. Scenes without a point light are left unchanged.
+    pub fn set_point_light_profile(&mut self, profile: Framebuffer) {
+        let (position, intensity) = point_light_setup();
+        let mut light = box PointLight::new(position);
+        light.intensity = intensity;
+        light.set_profile(profile, vec3(0.0, 0.0, -1.0));
+        self.replace_point_light(light);
+    }
+
+    /// Removes the directional sun, for backgrounds that bring their own. Scenes without one
+    /// are left unchanged.
+    pub fn remove_sun(&mut self) {
+        match self.sun_light.take() {
+            Some(id) => {
+                self.remove_light(id);
+                self.build_light_distribution();
+            },
+            None => (),
+        }
+    }
+
+    /// Removes a light, shifting the ids of the lights after it.
+    fn remove_light(&mut self, light_id: uint) {
+        self.lights.remove(light_id);
+
+        let shift = |id: uint| if id > light_id { id - 1 } else { id };
+        self.background = self.background.map(|id| shift(id));
+        self.point_light = self.point_light.map(|id| shift(id));
+        for (_, id) in self.emitter_to_light.iter_mut() {
+            if *id > light_id as int {
+                *id -= 1;
+            }
+        }
+    }
+
+    fn replace_point_light(&mut self, light: Box<AbstractLight + Sync + 'static>) {
+        match self.point_light {
+            Some(id) => {
+                self.lights[mut][id] = light;
+                self.build_light_distribution();
+            },
+            None => (),
+        }
+    }
+
+    pub fn set_light_selection(&mut self, light_selection: LightSelection) {
+        self.light_selection = light_selection;
+        self.build_light_distribution();
+    }
+
+    /// Chooses a light to sample for the shading point `position` with surface normal `normal`,
+    /// which may be zero. Returns the light id and the probability of picking it, or `None` if
+    /// no light can contribute.
+    ///
+    /// The light sampling integrators still have to be written, so nothing calls this yet.
+    /// They should pick lights through here and weight by `light_pick_pdf`.
+    pub fn pick_light(&self, position: Vec3f, normal: Vec3f, u: f32) -> Option<(int, f32)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let light_bvh = match self.light_bvh {
+            Some(ref light_bvh) => light_bvh,
+            None => {
+                let (index, prob) = self.light_distribution.sample_discrete(u);
+                return Some((index as int, prob));
+            },
+        };
+
+        let infinite_prob = self.infinite_light_prob(light_bvh);
+        if u < infinite_prob {
+            let count = self.infinite_lights.len();
+            let index = min((u / infinite_prob * count as f32) as uint, count - 1);
+            Some((self.infinite_lights[index] as int, infinite_prob / count as f32))
+        } else {
+            let u = ((u - infinite_prob) / (1.0 - infinite_prob)).min(0.99999994);
+            light_bvh.sample(position, normal, u).map(|(light_id, prob)| {
+                (light_id as int, prob * (1.0 - infinite_prob))
+            })
+        }
+    }
+
+    /// Probability of `pick_light` returning `light_id` for the same shading point.
+    pub fn light_pick_pdf(&self, position: Vec3f, normal: Vec3f, light_id: int) -> f32 {
+        let light_bvh = match self.light_bvh {
+            Some(ref light_bvh) => light_bvh,
+            None => return self.light_distribution.discrete_pdf(light_id as uint),
+        };
+
+        let infinite_prob = self.infinite_light_prob(light_bvh);
+        if self.infinite_lights.contains(&(light_id as uint)) {
+            infinite_prob / self.infinite_lights.len() as f32
+        } else {
+            light_bvh.pdf(position, normal, light_id as uint) * (1.0 - infinite_prob)
+        }
+    }
+
+    /// Probability of picking any infinite light instead of traversing the light BVH.
+    fn infinite_light_prob(&self, light_bvh: &LightBvh) -> f32 {
+        let num_infinite = self.infinite_lights.len() as f32;
+        let num_bvh = if light_bvh.is_empty() { 0.0 } else { 1.0 };
+        if num_infinite == 0.0 { 0.0 } else { num_infinite / (num_infinite + num_bvh) }
+    }
+
+    fn build_light_distribution(&mut self) {
+        let weights = match self.light_selection {
+            LightSelection::Uniform | LightSelection::Bvh =>
+                Vec::from_elem(self.lights.len(), 1.0),
+            LightSelection::Power =>
+                self.lights.iter().map(|l| l.power(&self.scene_sphere)).collect(),
+        };
+        self.light_distribution = Distribution1D::new(weights);
+
+        self.infinite_lights.clear();
+        self.light_bvh = match self.light_selection {
+            LightSelection::Bvh => {
+                let mut bounded = Vec::new();
+                for (i, light) in self.lights.iter().enumerate() {
+                    match light.bounds(&self.scene_sphere) {
+                        Some(bounds) => bounded.push((i, bounds)),
+                        None => self.infinite_lights.push(i),
+                    }
+                }
+                Some(LightBvh::build(bounded, self.lights.len()))
+            },
+            _ => None,
+        };
+    }
+
+    pub fn load_cornell_box(resolution: Vec2i, mut box_mask: BoxMask,
+                            camera_settings: &CameraSettings) -> Scene {
         let (name, acronym) = Scene::get_scene_name(box_mask);
 
         if (box_mask & BOTH_LARGE_SPHERES) == BOTH_LARGE_SPHERES {
@@ -67,11 +297,21 @@ impl Scene {
 
         let light_box = !light_point;
 
+        let light_emission = vec3s(if light_box { 25.03329895614464 } else { 0.95492965 });
         let materials = vec![
-            // 0) light1, will only emit
-            Material::new(),
+            // 0) light1, will only emit. The ceiling and light box triangles are wound to face
+            // down into the box.
+            Material {
+                emission: light_emission,
+                emission_mode: EmissionMode::Front,
+                ..Material::new()
+            },
             // 1) light2, will only emit
-            Material::new(),
+            Material {
+                emission: light_emission,
+                emission_mode: EmissionMode::Front,
+                ..Material::new()
+            },
 
             // 2) glossy white floor
             Material {
@@ -120,16 +360,7 @@ impl Scene {
         ];
 
         // Cornell box
-        let cb = [
-            vec3(-1.27029,  1.30455, -1.28002),
-            vec3( 1.28975,  1.30455, -1.28002),
-            vec3( 1.28975,  1.30455,  1.28002),
-            vec3(-1.27029,  1.30455,  1.28002),
-            vec3(-1.27029, -1.25549, -1.28002),
-            vec3( 1.28975, -1.25549, -1.28002),
-            vec3( 1.28975, -1.25549,  1.28002),
-            vec3(-1.27029, -1.25549,  1.28002),
-        ];
+        let cb = cornell_box_corners();
 
         let mut geometry_list = GeometryList::new();
 
@@ -214,40 +445,63 @@ impl Scene {
             geometry_list.geometry.push(box Triangle::new(lb[5], lb[0], lb[1], mat_b));
         }
 
-        // Lights
+        // Lights, first the emissive geometry
+        let emissive: Vec<bool> = materials.iter().map(|m| m.is_emissive()).collect();
         let mut lights : Vec<Box<AbstractLight + Sync + 'static>> = Vec::new();
-        let mut material_to_light = VecMap::new();
-        if light_ceiling && !light_box {
-            let mut l = box AreaLight::new(cb[2], cb[6], cb[7]);
-            l.intensity = vec3s(0.95492965);
-            lights.push(l);
-            material_to_light.insert(0, 0);
-
-            let mut l = box AreaLight::new(cb[7], cb[3], cb[2]);
-            l.intensity = vec3s(0.95492965);
-            lights.push(l);
-            material_to_light.insert(1, 1);
-        } else if light_ceiling && light_box {
-            let mut l = box AreaLight::new(cb[0], cb[5], cb[4]);
-            l.intensity = vec3s(25.03329895614464);
-            lights.push(l);
-            material_to_light.insert(0, 0);
-
-            let mut l = box AreaLight::new(cb[5], cb[0], cb[1]);
-            l.intensity = vec3s(25.03329895614464);
-            lights.push(l);
-            material_to_light.insert(1, 1);
+        let mut emitter_to_light = HashMap::new();
+        for emitter in geometry_list.emitters(emissive[]).into_iter() {
+            emitter_to_light.insert((emitter.mesh_id, emitter.prim_id), lights.len() as int);
+            let material = &materials[emitter.mat_id as uint];
+            let emission = material.emission;
+            match emitter.shape {
+                EmitterShape::Triangle(p0, p1, p2) => {
+                    let mut l = box AreaLight::new(p0, p1, p2);
+                    l.intensity = emission;
+                    l.emission_mode = material.emission_mode;
+                    lights.push(l);
+                },
+                EmitterShape::Sphere(center, radius) => {
+                    let mut l = box SphereLight::new(center, radius);
+                    l.intensity = emission;
+                    lights.push(l);
+                },
+                EmitterShape::Disk(center, normal, radius) => {
+                    let mut l = box ShapeLight::new(
+                        box Disk::new(center, normal, radius, emitter.mat_id));
+                    l.intensity = emission;
+                    l.emission_mode = material.emission_mode;
+                    lights.push(l);
+                },
+                EmitterShape::Quad(p0, e1, e2) => {
+                    let mut l = box ShapeLight::new(box Quad::new(p0, e1, e2, emitter.mat_id));
+                    l.intensity = emission;
+                    l.emission_mode = material.emission_mode;
+                    lights.push(l);
+                },
+                EmitterShape::Cylinder(base, axis, radius, height) => {
+                    let mut l = box ShapeLight::new(
+                        box Cylinder::new(base, axis, radius, height, emitter.mat_id));
+                    l.intensity = emission;
+                    l.emission_mode = material.emission_mode;
+                    lights.push(l);
+                },
+            }
         }
 
+        let mut sun_light = None;
         if light_sun {
             let mut l = box DirectionalLight::new(vec3(-1.0, 1.5, -1.0));
             l.intensity = vec3(0.5, 0.2, 0.0) * vec3s(20.0);
+            sun_light = Some(lights.len());
             lights.push(l);
         }
 
+        let mut point_light = None;
         if light_point {
-            let mut l = box PointLight::new(vec3(0.0, -0.5, 1.0));
-            l.intensity = vec3s(70.0 * (INV_PI * 0.25));
+            let (position, intensity) = point_light_setup();
+            let mut l = box PointLight::new(position);
+            l.intensity = intensity;
+            point_light = Some(lights.len());
             lights.push(l);
         }
 
@@ -259,34 +513,43 @@ impl Scene {
             lights.push(l);
         }
 
-        Scene {
+        let eye = vec3(-0.0439815, -4.12529, 0.222539);
+        let view = CameraView::new(
+            CameraPose::look_at(eye, eye + vec3(0.00688625, 0.998505, -0.0542161),
+                                vec3(3.73896e-4, 0.0542148, 0.998529)),
+            Fov::Horizontal(45.0));
+        let camera = camera_settings.create(&view, vec2(resolution.x as f32, resolution.y as f32),
+                                            geometry_list.bbox().centroid());
+
+        let mut scene = Scene {
             geometry: geometry_list,
-            camera: Camera::new(
-                vec3(-0.0439815, -4.12529,   0.222539),
-                vec3( 0.00688625, 0.998505, -0.0542161),
-                vec3( 3.73896e-4, 0.0542148, 0.998529),
-                vec2(resolution.x as f32, resolution.y as f32), 45.0),
+            camera: camera,
             materials: materials,
             lights: lights,
-            material_to_light: material_to_light,
+            emitter_to_light: emitter_to_light,
             scene_sphere: SceneSphere {
                 scene_center: vec3s(0.0), scene_radius: 0.0, inv_scene_radius_sqr: 0.0 },
             background: background_light,
+            point_light: point_light,
+            sun_light: sun_light,
+            light_selection: LightSelection::Power,
+            light_distribution: Distribution1D::new(Vec::new()),
+            light_bvh: None,
+            infinite_lights: Vec::new(),
             scene_name: name,
             scene_acronym: acronym,
-        }
+        };
+        scene.build_light_distribution();
+        scene
     }
 
     pub fn build_scene_sphere(&mut self) {
-        let mut bbox_min = vec3s(1e36);
-        let mut bbox_max = vec3s(-1e36);
-        self.geometry.grow_bbox(&mut bbox_min, &mut bbox_max);
-
-        let radius2 = (bbox_max - bbox_min).length_sqr();
+        let bbox = self.geometry.bbox();
 
-        self.scene_sphere.scene_center = (bbox_max + bbox_min) * vec3s(0.5);
-        self.scene_sphere.scene_radius = radius2.sqrt() * 0.5;
+        self.scene_sphere.scene_center = bbox.centroid();
+        self.scene_sphere.scene_radius = bbox.extent().length() * 0.5;
         self.scene_sphere.inv_scene_radius_sqr = 1.0 / sqr(self.scene_sphere.scene_radius);
+        self.build_light_distribution();
     }
 
     fn get_scene_name(_box_mask: BoxMask) -> (String, String) {
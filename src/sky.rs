@@ -0,0 +1,149 @@
+use math::{Vec3f, vec3, vec3s, PI};
+use std::num::{Float, FloatMath};
+
+/// Angular radius of the sun disk, in degrees.
+pub const SUN_ANGULAR_RADIUS: f32 = 0.2667;
+
+/// Luminance of the sun outside the atmosphere, in the units of the sky model (kcd/m^2).
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+/// Perez et al. luminance distribution coefficients.
+struct Perez {
+    a: f32,
+    b: f32,
+    c: f32,
+    d: f32,
+    e: f32,
+}
+
+impl Perez {
+    /// `cos_theta` is the cosine of the zenith angle of the view direction and `gamma` its angle
+    /// to the sun.
+    fn evaluate(&self, cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+        (1.0 + self.a * (self.b / cos_theta).exp()) *
+            (1.0 + self.c * (self.d * gamma).exp() + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+/// Analytic daylight model of Preetham, Shirley and Smits ("A Practical Analytic Model for
+/// Daylight", SIGGRAPH 1999), with +z up. Radiance is in kcd/m^2, converted to linear sRGB.
+pub struct PreethamSky {
+    sun_direction: Vec3f,
+    turbidity: f32,
+    perez_y: Perez,
+    perez_x: Perez,
+    perez_yc: Perez,
+    /// Zenith luminance divided by the Perez function at the zenith, for each of Y, x and y.
+    zenith: Vec3f,
+}
+
+impl PreethamSky {
+    /// `sun_direction` points towards the sun. `turbidity` ranges from about 2 for a clear
+    /// sky to 10 for a hazy one.
+    pub fn new(sun_direction: Vec3f, turbidity: f32) -> PreethamSky {
+        let sun_direction = sun_direction.normalized();
+        let t = turbidity;
+        // The model is only valid above the horizon
+        let theta_s = sun_direction.z.max(0.0).min(1.0).acos();
+
+        let perez_y = Perez {
+            a: 0.1787 * t - 1.4630, b: -0.3554 * t + 0.4275, c: -0.0227 * t + 5.3251,
+            d: 0.1206 * t - 2.5771, e: -0.0670 * t + 0.3703,
+        };
+        let perez_x = Perez {
+            a: -0.0193 * t - 0.2592, b: -0.0665 * t + 0.0008, c: -0.0004 * t + 0.2125,
+            d: -0.0641 * t - 0.8989, e: -0.0033 * t + 0.0452,
+        };
+        let perez_yc = Perez {
+            a: -0.0167 * t - 0.2608, b: -0.0950 * t + 0.0092, c: -0.0079 * t + 0.2102,
+            d: -0.0441 * t - 1.6537, e: -0.0109 * t + 0.0529,
+        };
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x =
+            t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) +
+            t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) +
+            (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_yc =
+            t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) +
+            t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) +
+            (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        let cos_s = theta_s.cos();
+        let zenith = vec3(zenith_y / perez_y.evaluate(1.0, theta_s, cos_s),
+                          zenith_x / perez_x.evaluate(1.0, theta_s, cos_s),
+                          zenith_yc / perez_yc.evaluate(1.0, theta_s, cos_s));
+
+        PreethamSky {
+            sun_direction: sun_direction,
+            turbidity: turbidity,
+            perez_y: perez_y,
+            perez_x: perez_x,
+            perez_yc: perez_yc,
+            zenith: zenith,
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vec3f {
+        self.sun_direction
+    }
+
+    /// Sky radiance in `direction`, without the sun. Black below the horizon.
+    pub fn radiance(&self, direction: Vec3f) -> Vec3f {
+        if direction.z <= 0.0 {
+            return vec3s(0.0);
+        }
+
+        // Keep the Perez function finite at the horizon
+        let cos_theta = direction.z.max(0.01);
+        let cos_gamma = direction.dot(self.sun_direction).max(-1.0).min(1.0);
+        let gamma = cos_gamma.acos();
+
+        let y = self.zenith.x * self.perez_y.evaluate(cos_theta, gamma, cos_gamma);
+        let x = self.zenith.y * self.perez_x.evaluate(cos_theta, gamma, cos_gamma);
+        let yc = self.zenith.z * self.perez_yc.evaluate(cos_theta, gamma, cos_gamma);
+        xyy_to_rgb(x, yc, y.max(0.0))
+    }
+
+    /// Radiance of the sun disk after extinction through the atmosphere. Black once the sun
+    /// has set.
+    pub fn sun_radiance(&self) -> Vec3f {
+        if self.sun_direction.z <= 0.0 {
+            return vec3s(0.0);
+        }
+
+        // Relative optical air mass (Kasten)
+        let theta_deg = self.sun_direction.z.min(1.0).acos() * 180.0 / PI;
+        let m = 1.0 / (self.sun_direction.z + 0.15 * (93.885 - theta_deg).powf(-1.253));
+
+        // Rayleigh and aerosol (Angstrom) transmittance at representative wavelengths of the
+        // RGB primaries, in micrometers
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let wavelengths = [0.680f32, 0.550, 0.440];
+        let mut transmittance = vec3s(0.0);
+        for c in range(0, 3) {
+            let lambda = wavelengths[c];
+            let tau_r = (-0.008735 * lambda.powf(-4.08) * m).exp();
+            let tau_a = (-beta * lambda.powf(-1.3) * m).exp();
+            transmittance[c] = tau_r * tau_a;
+        }
+
+        transmittance * vec3s(SUN_LUMINANCE)
+    }
+}
+
+/// Converts CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Vec3f {
+    if y <= 0.0 {
+        return vec3s(0.0);
+    }
+    let cx = x * lum / y;
+    let cz = (1.0 - x - y) * lum / y;
+
+    vec3( 3.2404542 * cx - 1.5371385 * lum - 0.4985314 * cz,
+         -0.9692660 * cx + 1.8760108 * lum + 0.0415560 * cz,
+          0.0556434 * cx - 0.2040259 * lum + 1.0572252 * cz).max_with(vec3s(0.0))
+}
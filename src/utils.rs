@@ -1,7 +1,138 @@
-use math::Vec3f;
+use math::{Vec2f, Vec3f, vec2, vec3, sqr, PI, INV_PI};
+use std::num::{Float, FloatMath};
+
+pub const EPS_COSINE: f32 = 1e-6;
 
 pub fn luminance(rgb: Vec3f) -> f32 {
     0.212671 * rgb.x +
         0.715160 * rgb.y +
         0.072169 * rgb.z
 }
+
+pub fn fresnel_dielectric(cos_inc: f32, ior: f32) -> f32 {
+    if ior < 0.0 {
+        return 1.0;
+    }
+
+    let (cos_inc, eta_inc_over_eta_trans) = if cos_inc < 0.0 {
+        (-cos_inc, ior)
+    } else {
+        (cos_inc, 1.0 / ior)
+    };
+
+    let sin_trans2 = sqr(eta_inc_over_eta_trans) * (1.0 - sqr(cos_inc));
+    let cos_trans = (1.0 - sin_trans2).max(0.0).sqrt();
+
+    let term1 = eta_inc_over_eta_trans * cos_trans;
+    let r_parallel = (cos_inc - term1) / (cos_inc + term1);
+
+    let term2 = eta_inc_over_eta_trans * cos_inc;
+    let r_perpendicular = (term2 - cos_trans) / (term2 + cos_trans);
+
+    0.5 * (sqr(r_parallel) + sqr(r_perpendicular))
+}
+
+/// Reflects a direction given in local coordinates around the normal (z axis).
+pub fn reflect_local(v: Vec3f) -> Vec3f {
+    vec3(-v.x, -v.y, v.z)
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Sampling routines. All return the sampled value and its pdf.
+
+pub fn sample_cos_hemisphere_w(samples: Vec2f) -> (Vec3f, f32) {
+    let term1 = 2.0 * PI * samples.x;
+    let term2 = (1.0 - samples.y).sqrt();
+
+    let ret = vec3(term1.cos() * term2, term1.sin() * term2, samples.y.sqrt());
+    (ret, ret.z * INV_PI)
+}
+
+pub fn cos_hemisphere_pdf_w(normal: Vec3f, direction: Vec3f) -> f32 {
+    normal.dot(direction).max(0.0) * INV_PI
+}
+
+pub fn sample_power_cos_hemisphere_w(samples: Vec2f, power: f32) -> (Vec3f, f32) {
+    let term1 = 2.0 * PI * samples.x;
+    let term2 = samples.y.powf(1.0 / (power + 1.0));
+    let term3 = (1.0 - term2 * term2).sqrt();
+
+    let pdf = (power + 1.0) * term2.powf(power) * (0.5 * INV_PI);
+    (vec3(term1.cos() * term3, term1.sin() * term3, term2), pdf)
+}
+
+pub fn power_cos_hemisphere_pdf_w(normal: Vec3f, direction: Vec3f, power: f32) -> f32 {
+    let cos_theta = normal.dot(direction).max(0.0);
+    (power + 1.0) * cos_theta.powf(power) * (0.5 * INV_PI)
+}
+
+/// Returns barycentric coordinates of the first two vertices.
+pub fn sample_uniform_triangle(samples: Vec2f) -> Vec2f {
+    let x_sqr = samples.x.sqrt();
+    vec2(1.0 - x_sqr, samples.y * x_sqr)
+}
+
+pub fn sample_uniform_sphere_w(samples: Vec2f) -> (Vec3f, f32) {
+    let term1 = 2.0 * PI * samples.x;
+    let term2 = 2.0 * (samples.y - samples.y * samples.y).sqrt();
+
+    let ret = vec3(term1.cos() * term2, term1.sin() * term2, 1.0 - 2.0 * samples.y);
+    (ret, INV_PI * 0.25)
+}
+
+pub fn uniform_sphere_pdf_w() -> f32 {
+    INV_PI * 0.25
+}
+
+/// Samples directions within `acos(cos_max)` of +z.
+pub fn sample_uniform_cone_w(samples: Vec2f, cos_max: f32) -> (Vec3f, f32) {
+    let cos_theta = 1.0 - samples.y * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * samples.x;
+
+    let ret = vec3(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
+    (ret, uniform_cone_pdf_w(cos_max))
+}
+
+pub fn uniform_cone_pdf_w(cos_max: f32) -> f32 {
+    0.5 * INV_PI / (1.0 - cos_max)
+}
+
+/// Shirley's concentric mapping of the unit square to the unit disc.
+pub fn sample_concentric_disc(samples: Vec2f) -> Vec2f {
+    let a = 2.0 * samples.x - 1.0;
+    let b = 2.0 * samples.y - 1.0;
+
+    let (r, phi) = if a == 0.0 && b == 0.0 {
+        (0.0, 0.0)
+    } else if a > -b {
+        if a > b {
+            (a, (PI / 4.0) * (b / a))
+        } else {
+            (b, (PI / 4.0) * (2.0 - a / b))
+        }
+    } else {
+        if a < b {
+            (-a, (PI / 4.0) * (4.0 + b / a))
+        } else {
+            (-b, if b != 0.0 { (PI / 4.0) * (6.0 - a / b) } else { 0.0 })
+        }
+    };
+
+    vec2(r * phi.cos(), r * phi.sin())
+}
+
+pub fn concentric_disc_pdf_a() -> f32 {
+    INV_PI
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Pdf conversions
+
+pub fn pdf_w_to_a(pdf_w: f32, dist: f32, cos_there: f32) -> f32 {
+    pdf_w * cos_there.abs() / sqr(dist)
+}
+
+pub fn pdf_a_to_w(pdf_a: f32, dist: f32, cos_there: f32) -> f32 {
+    pdf_a * sqr(dist) / cos_there.abs()
+}